@@ -0,0 +1,291 @@
+// Build a file system image for the kernel.
+//
+//   mkfs fs.img files...
+//
+// Creates fs.img with a root directory holding each of files.
+// A leading '_' is stripped from file names, so user programs can
+// be built as user/_name without clashing with their sources.
+
+use std::cmp::min;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::process::exit;
+
+// Share the kernel's parameters and on-disk format.
+// mkfs needs only some of what the kernel does with them.
+#[allow(dead_code)]
+#[path = "../../src/fs/layout.rs"]
+mod layout;
+#[allow(dead_code)]
+#[path = "../../src/params.rs"]
+mod params;
+
+use layout::*;
+use params::{BSIZE, FSSIZE, LOGSIZE};
+
+const NINODES: u32 = 200;
+
+// Disk layout:
+// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+
+const NBITMAP: u32 = FSSIZE as u32 / BPB + 1;
+const NINODEBLOCKS: u32 = NINODES / IPB + 1;
+const NLOG: u32 = LOGSIZE as u32;
+
+// Number of meta blocks (boot, sb, nlog, inode, bitmap)
+const NMETA: u32 = 2 + NLOG + NINODEBLOCKS + NBITMAP;
+// Number of data blocks
+const NBLOCKS: u32 = FSSIZE as u32 - NMETA;
+
+const _: () = assert!(BSIZE.is_multiple_of(size_of::<Dinode>()));
+const _: () = assert!(BSIZE.is_multiple_of(size_of::<Dirent>()));
+
+// The kernel is little-endian; convert to its byte order.
+fn xshort(x: i16) -> i16 {
+    x.to_le()
+}
+
+fn xint(x: u32) -> u32 {
+    x.to_le()
+}
+
+// View a plain-old-data on-disk struct as bytes.
+fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T: Copy>(v: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v as *mut T as *mut u8, size_of::<T>()) }
+}
+
+struct Mkfs {
+    img: File,
+    sb: SuperBlock,
+    freeinode: u32,
+    freeblock: u32,
+}
+
+impl Mkfs {
+    fn wsect(&mut self, sec: u32, buf: &[u8; BSIZE]) {
+        self.img
+            .seek(SeekFrom::Start(sec as u64 * BSIZE as u64))
+            .expect("lseek");
+        self.img.write_all(buf).expect("write");
+    }
+
+    fn rsect(&mut self, sec: u32, buf: &mut [u8; BSIZE]) {
+        self.img
+            .seek(SeekFrom::Start(sec as u64 * BSIZE as u64))
+            .expect("lseek");
+        self.img.read_exact(buf).expect("read");
+    }
+
+    fn winode(&mut self, inum: u32, ip: &Dinode) {
+        let bn = iblock(inum, &self.sb);
+        let mut buf = [0u8; BSIZE];
+        self.rsect(bn, &mut buf);
+        let off = (inum % IPB) as usize * size_of::<Dinode>();
+        buf[off..off + size_of::<Dinode>()].copy_from_slice(as_bytes(ip));
+        self.wsect(bn, &buf);
+    }
+
+    fn rinode(&mut self, inum: u32) -> Dinode {
+        let bn = iblock(inum, &self.sb);
+        let mut buf = [0u8; BSIZE];
+        self.rsect(bn, &mut buf);
+        let off = (inum % IPB) as usize * size_of::<Dinode>();
+        let mut din = Dinode::default();
+        as_bytes_mut(&mut din).copy_from_slice(&buf[off..off + size_of::<Dinode>()]);
+        din
+    }
+
+    fn ialloc(&mut self, type_: i16) -> u32 {
+        let inum = self.freeinode;
+        self.freeinode += 1;
+
+        let din = Dinode {
+            type_: xshort(type_),
+            nlink: xshort(1),
+            size: xint(0),
+            ..Default::default()
+        };
+        self.winode(inum, &din);
+        inum
+    }
+
+    // Mark the first used blocks as in use in the free bitmap.
+    fn balloc(&mut self, used: u32) {
+        println!("balloc: first {} blocks have been allocated", used);
+        assert!(used < BPB);
+        let mut buf = [0u8; BSIZE];
+        for i in 0..used {
+            buf[(i / 8) as usize] |= 0x1 << (i % 8);
+        }
+        println!("balloc: write bitmap block at sector {}", self.sb.bmapstart);
+        let bmapstart = self.sb.bmapstart;
+        self.wsect(bmapstart, &buf);
+    }
+
+    fn iappend(&mut self, inum: u32, data: &[u8]) {
+        let mut din = self.rinode(inum);
+        let mut off = u32::from_le(din.size) as usize;
+        let mut p = data;
+        while !p.is_empty() {
+            let fbn = off / BSIZE;
+            assert!(fbn < MAXFILE, "file too large");
+            let x = if fbn < NDIRECT {
+                if u32::from_le(din.addrs[fbn]) == 0 {
+                    din.addrs[fbn] = xint(self.freeblock);
+                    self.freeblock += 1;
+                }
+                u32::from_le(din.addrs[fbn])
+            } else {
+                if u32::from_le(din.addrs[NDIRECT]) == 0 {
+                    din.addrs[NDIRECT] = xint(self.freeblock);
+                    self.freeblock += 1;
+                }
+                let mut indirect = [0u8; BSIZE];
+                let ibn = u32::from_le(din.addrs[NDIRECT]);
+                self.rsect(ibn, &mut indirect);
+                let at = (fbn - NDIRECT) * size_of::<u32>();
+                let mut entry = u32::from_le_bytes(indirect[at..at + 4].try_into().unwrap());
+                if entry == 0 {
+                    entry = self.freeblock;
+                    self.freeblock += 1;
+                    indirect[at..at + 4].copy_from_slice(&entry.to_le_bytes());
+                    self.wsect(ibn, &indirect);
+                }
+                entry
+            };
+            let n1 = min(p.len(), (fbn + 1) * BSIZE - off);
+            let mut buf = [0u8; BSIZE];
+            self.rsect(x, &mut buf);
+            let start = off - fbn * BSIZE;
+            buf[start..start + n1].copy_from_slice(&p[..n1]);
+            self.wsect(x, &buf);
+            off += n1;
+            p = &p[n1..];
+        }
+        din.size = xint(off as u32);
+        self.winode(inum, &din);
+    }
+
+    // Add a directory entry for inum called name to directory dir.
+    fn dirent(&mut self, dir: u32, inum: u32, name: &str) {
+        let mut de = Dirent {
+            inum: (inum as u16).to_le(),
+            ..Default::default()
+        };
+        let n = min(name.len(), DIRSIZ);
+        de.name[..n].copy_from_slice(&name.as_bytes()[..n]);
+        self.iappend(dir, as_bytes(&de));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: mkfs fs.img files...");
+        exit(1);
+    }
+
+    let img = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args[1])
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", args[1], e);
+            exit(1);
+        });
+
+    let sb = SuperBlock {
+        magic: FSMAGIC,
+        size: FSSIZE as u32,
+        nblocks: NBLOCKS,
+        ninodes: NINODES,
+        nlog: NLOG,
+        logstart: 2,
+        inodestart: 2 + NLOG,
+        bmapstart: 2 + NLOG + NINODEBLOCKS,
+    };
+
+    println!(
+        "nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) \
+         blocks {} total {}",
+        NMETA, NLOG, NINODEBLOCKS, NBITMAP, NBLOCKS, FSSIZE
+    );
+
+    let mut fs = Mkfs {
+        img,
+        sb,
+        freeinode: 1,
+        freeblock: NMETA, // the first free block that we can allocate
+    };
+
+    let zeroes = [0u8; BSIZE];
+    for i in 0..FSSIZE as u32 {
+        fs.wsect(i, &zeroes);
+    }
+
+    let mut buf = [0u8; BSIZE];
+    let disk_sb = SuperBlock {
+        magic: xint(sb.magic),
+        size: xint(sb.size),
+        nblocks: xint(sb.nblocks),
+        ninodes: xint(sb.ninodes),
+        nlog: xint(sb.nlog),
+        logstart: xint(sb.logstart),
+        inodestart: xint(sb.inodestart),
+        bmapstart: xint(sb.bmapstart),
+    };
+    buf[..size_of::<SuperBlock>()].copy_from_slice(as_bytes(&disk_sb));
+    fs.wsect(1, &buf);
+
+    let rootino = fs.ialloc(T_DIR);
+    assert_eq!(rootino, ROOTINO);
+
+    fs.dirent(rootino, rootino, ".");
+    fs.dirent(rootino, rootino, "..");
+
+    for arg in &args[2..] {
+        let path = Path::new(arg);
+        let mut name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_else(|| {
+                eprintln!("{}: bad file name", arg);
+                exit(1);
+            });
+        // Skip leading _ in name when writing to file system.
+        // The binaries are named _rm, _cat, etc. to keep the
+        // build operating system from trying to execute them
+        // in place of system binaries like rm and cat.
+        if let Some(stripped) = name.strip_prefix('_') {
+            name = stripped;
+        }
+        assert!(name.len() <= DIRSIZ, "{}: name too long", name);
+
+        let data = std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", arg, e);
+            exit(1);
+        });
+
+        let inum = fs.ialloc(T_FILE);
+        fs.dirent(rootino, inum, name);
+        fs.iappend(inum, &data);
+    }
+
+    // fix size of root inode dir
+    let mut din = fs.rinode(rootino);
+    let off = u32::from_le(din.size);
+    din.size = xint(off.div_ceil(BSIZE as u32) * BSIZE as u32);
+    fs.winode(rootino, &din);
+
+    let used = fs.freeblock;
+    fs.balloc(used);
+}
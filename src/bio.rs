@@ -0,0 +1,121 @@
+// Buffer cache.
+//
+// The buffer cache holds cached copies of disk block contents.
+// Caching disk blocks in memory reduces the number of disk reads
+// and also provides a synchronization point for disk blocks used
+// by multiple processes.
+//
+// Interface:
+// * To get a buffer for a particular disk block, call bread.
+// * After changing buffer data, call bwrite to write it to disk
+//     (file system code goes through log_write instead).
+// * When done with the buffer, call brelse.
+// * Do not use the buffer after calling brelse.
+// * Only one process at a time can use a buffer,
+//     so do not keep them longer than necessary.
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use crate::params::NBUF;
+use crate::spin_lock::SpinLock;
+use crate::virtio::virtio_blk::{virtio_disk_rw, DiskBuffer};
+
+struct Bcache {
+    buf: [DiskBuffer; NBUF],
+    // bumped on every final brelse(); the unused buffer with the
+    // smallest last_use is the least recently used one.
+    clock: u64,
+}
+
+static mut bcache: Bcache = unsafe { MaybeUninit::zeroed().assume_init() };
+static bcache_lock: SpinLock = SpinLock::new();
+
+// Look through buffer cache for block on device dev.
+// If not found, allocate a buffer.
+// In either case, return locked buffer.
+fn bget(dev: u32, blockno: u32) -> &'static mut DiskBuffer {
+    bcache_lock.lock();
+    let bc = unsafe { &mut *addr_of_mut!(bcache) };
+
+    // Is the block already cached?
+    if let Some(i) = bc
+        .buf
+        .iter()
+        .position(|b| b.dev == dev && b.blockno == blockno)
+    {
+        let b = &mut bc.buf[i];
+        b.refcnt += 1;
+        bcache_lock.unlock();
+        b.lock.lock();
+        return b;
+    }
+
+    // Not cached.
+    // Recycle the least recently used (LRU) unused buffer.
+    let b = bc
+        .buf
+        .iter_mut()
+        .filter(|b| b.refcnt == 0)
+        .min_by_key(|b| b.last_use)
+        .expect("bget: no buffers");
+    b.dev = dev;
+    b.blockno = blockno;
+    b.valid = false;
+    b.refcnt = 1;
+    bcache_lock.unlock();
+    b.lock.lock();
+    b
+}
+
+// Return a locked buf with the contents of the indicated block.
+pub fn bread(dev: u32, blockno: u32) -> &'static mut DiskBuffer {
+    let b = bget(dev, blockno);
+    if !b.valid {
+        virtio_disk_rw(b, false);
+        b.valid = true;
+    }
+    b
+}
+
+// Write b's contents to disk.  Must be locked.
+pub fn bwrite(b: &mut DiskBuffer) {
+    if !b.lock.holding() {
+        panic!("bwrite");
+    }
+    virtio_disk_rw(b, true);
+}
+
+// Release a locked buffer.
+// Stamp it as the most recently used.
+pub fn brelse(b: &mut DiskBuffer) {
+    if !b.lock.holding() {
+        panic!("brelse");
+    }
+
+    b.lock.unlock();
+
+    bcache_lock.lock();
+    b.refcnt -= 1;
+    if b.refcnt == 0 {
+        // no one is waiting for it.
+        let bc = unsafe { &mut *addr_of_mut!(bcache) };
+        bc.clock += 1;
+        b.last_use = bc.clock;
+    }
+    bcache_lock.unlock();
+}
+
+// Keep b in the cache until the log has installed it:
+// an extra reference stops bget() from recycling it.
+pub fn bpin(b: &mut DiskBuffer) {
+    bcache_lock.lock();
+    b.refcnt += 1;
+    bcache_lock.unlock();
+}
+
+pub fn bunpin(b: &mut DiskBuffer) {
+    bcache_lock.lock();
+    b.refcnt -= 1;
+    bcache_lock.unlock();
+}
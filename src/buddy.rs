@@ -0,0 +1,237 @@
+// Buddy allocator for physically contiguous runs of pages,
+// for kernel stacks and DMA memory for devices.
+//
+// The pool is a fixed region of 2^(NORDER-1) pages set aside by
+// kinit(). A block of order k is 2^k pages long and starts at a
+// multiple of its own size (relative to the pool). Its buddy is the
+// other half of the order k+1 block containing it; a freed block
+// is merged with its buddy whenever the buddy is free too.
+
+use core::ptr::{addr_of_mut, null_mut};
+
+use crate::println;
+use crate::riscv::PGSIZE;
+use crate::spin_lock::SpinLock;
+
+pub const NORDER: usize = 12; // orders 0..NORDER
+const POOLPAGES: usize = 1 << (NORDER - 1);
+pub const POOLSIZE: usize = POOLPAGES * PGSIZE;
+
+// marks a page that doesn't start a free block in free_order.
+const NOT_FREE: u8 = 0xff;
+
+// lives in the first page of every free block.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct Buddy {
+    base: usize,
+    free: [*mut FreeBlock; NORDER], // free lists, one per order
+    nfree: [usize; NORDER],         // length of each free list
+    // order of the free block starting at each page, or NOT_FREE.
+    free_order: [u8; POOLPAGES],
+}
+
+static mut buddy: Buddy = Buddy {
+    base: 0,
+    free: [null_mut(); NORDER],
+    nfree: [0; NORDER],
+    free_order: [NOT_FREE; POOLPAGES],
+};
+static buddy_lock: SpinLock = SpinLock::new();
+
+fn the_buddy() -> &'static mut Buddy {
+    unsafe { &mut *addr_of_mut!(buddy) }
+}
+
+// Smallest order whose blocks hold npages pages.
+fn order_for(npages: usize) -> usize {
+    npages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl Buddy {
+    fn page(&self, pa: usize) -> usize {
+        (pa - self.base) / PGSIZE
+    }
+
+    fn push(&mut self, pa: usize, order: usize) {
+        let b = pa as *mut FreeBlock;
+        unsafe {
+            (*b).prev = null_mut();
+            (*b).next = self.free[order];
+            if !self.free[order].is_null() {
+                (*self.free[order]).prev = b;
+            }
+        }
+        self.free[order] = b;
+        self.nfree[order] += 1;
+        let page = self.page(pa);
+        self.free_order[page] = order as u8;
+    }
+
+    fn remove(&mut self, pa: usize, order: usize) {
+        let b = pa as *mut FreeBlock;
+        unsafe {
+            if (*b).prev.is_null() {
+                self.free[order] = (*b).next;
+            } else {
+                (*(*b).prev).next = (*b).next;
+            }
+            if !(*b).next.is_null() {
+                (*(*b).next).prev = (*b).prev;
+            }
+        }
+        self.nfree[order] -= 1;
+        let page = self.page(pa);
+        self.free_order[page] = NOT_FREE;
+    }
+}
+
+// Hand the pool starting at base, POOLSIZE bytes long, to the allocator.
+pub fn buddyinit(base: usize) {
+    if base % PGSIZE != 0 {
+        panic!("buddyinit: base not aligned");
+    }
+    let bd = the_buddy();
+    bd.base = base;
+    bd.push(base, NORDER - 1);
+    buddy_check();
+}
+
+// Check that blocks split by allocation merge back together when
+// they are freed: afterwards the pool must be one block again.
+fn buddy_check() {
+    let a = buddy_alloc(1).expect("buddy_check");
+    let b = buddy_alloc(3).expect("buddy_check");
+    let c = buddy_alloc(1).expect("buddy_check");
+    if buddy_stats().fragmentation() == 0 {
+        panic!("buddy_check: not split");
+    }
+    buddy_free(b, 3);
+    buddy_free(a, 1);
+    buddy_free(c, 1);
+    let st = buddy_stats();
+    if st.free_pages != POOLPAGES || st.largest_free != POOLPAGES {
+        panic!("buddy_check: not merged");
+    }
+}
+
+// Allocate a physically contiguous run of at least npages pages,
+// rounded up to a power of two.
+// Returns None if no big enough block is free.
+pub fn buddy_alloc(npages: usize) -> Option<*mut u8> {
+    let order = order_for(npages);
+    if order >= NORDER {
+        return None;
+    }
+
+    buddy_lock.lock();
+    let bd = the_buddy();
+
+    // smallest free block that is big enough.
+    let mut k = match (order..NORDER).find(|&k| !bd.free[k].is_null()) {
+        Some(k) => k,
+        None => {
+            buddy_lock.unlock();
+            return None;
+        }
+    };
+    let pa = bd.free[k] as usize;
+    bd.remove(pa, k);
+
+    // split it, freeing the upper halves, until it is the right size.
+    while k > order {
+        k -= 1;
+        bd.push(pa + (PGSIZE << k), k);
+    }
+    buddy_lock.unlock();
+
+    Some(pa as *mut u8)
+}
+
+// Free a run returned by buddy_alloc(npages), with the same npages.
+pub fn buddy_free(pa: *mut u8, npages: usize) {
+    let mut order = order_for(npages);
+    let mut pa = pa as usize;
+
+    buddy_lock.lock();
+    let bd = the_buddy();
+    if pa % PGSIZE != 0 || pa < bd.base || pa >= bd.base + POOLSIZE || order >= NORDER {
+        panic!("buddy_free");
+    }
+    if (pa - bd.base) % (PGSIZE << order) != 0 {
+        panic!("buddy_free: misaligned block");
+    }
+    if bd.free_order[bd.page(pa)] != NOT_FREE {
+        panic!("buddy_free: double free");
+    }
+
+    // merge with the buddy while it is free and whole.
+    while order < NORDER - 1 {
+        let buddy_pa = bd.base + ((pa - bd.base) ^ (PGSIZE << order));
+        if bd.free_order[bd.page(buddy_pa)] != order as u8 {
+            break;
+        }
+        bd.remove(buddy_pa, order);
+        pa = pa.min(buddy_pa);
+        order += 1;
+    }
+    bd.push(pa, order);
+    buddy_lock.unlock();
+}
+
+#[derive(Clone, Copy)]
+pub struct BuddyStats {
+    pub free_blocks: [usize; NORDER], // free blocks of each order
+    pub free_pages: usize,
+    pub largest_free: usize, // pages in the largest free block
+}
+
+impl BuddyStats {
+    // How much of the free memory can't be handed out as one block,
+    // in percent: 0 when all of it is a single block.
+    pub fn fragmentation(&self) -> usize {
+        if self.free_pages == 0 {
+            return 0;
+        }
+        100 - self.largest_free * 100 / self.free_pages
+    }
+}
+
+pub fn buddy_stats() -> BuddyStats {
+    buddy_lock.lock();
+    let bd = the_buddy();
+    let mut st = BuddyStats {
+        free_blocks: bd.nfree,
+        free_pages: 0,
+        largest_free: 0,
+    };
+    buddy_lock.unlock();
+
+    for (k, &n) in st.free_blocks.iter().enumerate() {
+        st.free_pages += n << k;
+        if n > 0 {
+            st.largest_free = 1 << k;
+        }
+    }
+    st
+}
+
+// Print the allocator's state on the console, for debugging.
+pub fn buddy_dump() {
+    let st = buddy_stats();
+    println!(
+        "buddy: {} of {} pages free, largest block {} pages, fragmentation {}%",
+        st.free_pages,
+        POOLPAGES,
+        st.largest_free,
+        st.fragmentation()
+    );
+    for (k, &n) in st.free_blocks.iter().enumerate() {
+        if n > 0 {
+            println!("  order {}: {} free", k, n);
+        }
+    }
+}
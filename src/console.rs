@@ -0,0 +1,209 @@
+//
+// Console input and output, to the uart.
+// Reads are line at a time.
+// Implements special input characters:
+//   newline -- end of line
+//   control-h -- backspace
+//   control-u -- kill line
+//   control-d -- end of file
+//   control-p -- print memory allocator usage
+//
+
+use core::ptr::{addr_of, addr_of_mut};
+
+use crate::file::{devsw, Devsw, CONSOLE};
+use crate::kalloc::kmemdump;
+use crate::proc::{either_copyin, either_copyout, myproc, proc, sleep, wakeup};
+use crate::spin_lock::SpinLock;
+use crate::uart::{uart_init, uartputc, uartputc_sync};
+
+// Control-x
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+const INPUT_BUF_SIZE: usize = 128;
+
+struct Cons {
+    // input
+    buf: [u8; INPUT_BUF_SIZE],
+    r: u32, // Read index
+    w: u32, // Write index
+    e: u32, // Edit index
+}
+
+static mut cons: Cons = Cons {
+    buf: [0; INPUT_BUF_SIZE],
+    r: 0,
+    w: 0,
+    e: 0,
+};
+static cons_lock: SpinLock = SpinLock::new();
+
+fn the_cons() -> &'static mut Cons {
+    unsafe { &mut *addr_of_mut!(cons) }
+}
+
+// consoleread() sleeps on this until a whole line has arrived.
+fn read_chan() -> usize {
+    unsafe { addr_of!(cons.r) as usize }
+}
+
+// no input to read. consoleintr() moves cons.w while
+// consoleread() sleeps, so this must go to memory every time.
+fn cons_empty() -> bool {
+    unsafe { *addr_of!(cons.r) == *addr_of!(cons.w) }
+}
+
+// send one character to the uart.
+// called to echo input characters,
+// but not from write().
+fn consputc(c: u8) {
+    uartputc_sync(c);
+}
+
+// erase the last character on the screen.
+fn consbackspace() {
+    uartputc_sync(0x08);
+    uartputc_sync(b' ');
+    uartputc_sync(0x08);
+}
+
+//
+// user write()s to the console go here.
+//
+fn consolewrite(user_src: bool, src: u64, n: i32) -> i32 {
+    for i in 0..n {
+        let mut c = 0u8;
+        if either_copyin(&mut c, user_src, src + i as u64, 1).is_err() {
+            return i;
+        }
+        uartputc(c);
+    }
+    n
+}
+
+//
+// user read()s from the console go here.
+// copy (up to) a whole input line to dst.
+// user_dst indicates whether dst is a user
+// or kernel address.
+//
+fn consoleread(user_dst: bool, dst: u64, n: i32) -> i32 {
+    let target = n;
+    let mut n = n;
+    let mut dst = dst;
+
+    cons_lock.lock();
+    while n > 0 {
+        // wait until interrupt handler has put some
+        // input into cons.buf.
+        while cons_empty() {
+            if unsafe { proc[myproc().unwrap()].killed } {
+                cons_lock.unlock();
+                return -1;
+            }
+            sleep(read_chan(), &cons_lock);
+        }
+        let cn = the_cons();
+
+        let c = cn.buf[cn.r as usize % INPUT_BUF_SIZE];
+        cn.r = cn.r.wrapping_add(1);
+
+        if c == ctrl(b'D') {
+            // end-of-file
+            if n < target {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cn.r = cn.r.wrapping_sub(1);
+            }
+            break;
+        }
+
+        // copy the input byte to the user-space buffer.
+        if either_copyout(user_dst, dst, &c, 1).is_err() {
+            break;
+        }
+
+        dst += 1;
+        n -= 1;
+
+        if c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break;
+        }
+    }
+    cons_lock.unlock();
+
+    target - n
+}
+
+//
+// the console input interrupt handler.
+// uart_intr() calls this for input character.
+// do erase/kill processing, append to cons.buf,
+// wake up consoleread() if a whole line has arrived.
+//
+pub fn consoleintr(c: u8) {
+    cons_lock.lock();
+    let cn = the_cons();
+
+    match c {
+        c if c == ctrl(b'P') => {
+            // Print memory allocator usage.
+            kmemdump();
+        }
+        c if c == ctrl(b'U') => {
+            // Kill line.
+            while cn.e != cn.w && cn.buf[cn.e.wrapping_sub(1) as usize % INPUT_BUF_SIZE] != b'\n' {
+                cn.e = cn.e.wrapping_sub(1);
+                consbackspace();
+            }
+        }
+        c if c == ctrl(b'H') || c == 0x7f => {
+            // Backspace or Delete key
+            if cn.e != cn.w {
+                cn.e = cn.e.wrapping_sub(1);
+                consbackspace();
+            }
+        }
+        _ => {
+            if c != 0 && (cn.e.wrapping_sub(cn.r) as usize) < INPUT_BUF_SIZE {
+                let c = if c == b'\r' { b'\n' } else { c };
+
+                // echo back to the user.
+                consputc(c);
+
+                // store for consumption by consoleread().
+                cn.buf[cn.e as usize % INPUT_BUF_SIZE] = c;
+                cn.e = cn.e.wrapping_add(1);
+
+                if c == b'\n'
+                    || c == ctrl(b'D')
+                    || cn.e.wrapping_sub(cn.r) as usize == INPUT_BUF_SIZE
+                {
+                    // wake up consoleread() if a whole line (or end-of-file)
+                    // has arrived.
+                    cn.w = cn.e;
+                    wakeup(read_chan());
+                }
+            }
+        }
+    }
+
+    cons_lock.unlock();
+}
+
+pub fn consoleinit() {
+    uart_init();
+
+    // connect read and write system calls
+    // to consoleread and consolewrite.
+    unsafe {
+        (*addr_of_mut!(devsw))[CONSOLE] = Devsw {
+            read: Some(consoleread),
+            write: Some(consolewrite),
+        };
+    }
+}
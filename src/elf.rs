@@ -0,0 +1,48 @@
+// Format of an ELF executable file
+
+pub const ELF_MAGIC: u32 = 0x464C457F; // "\x7FELF" in little endian
+pub const ELFCLASS64: u8 = 2; // elf[0] of a 64-bit object
+pub const EM_RISCV: u16 = 243;
+
+// File header
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ElfHeader {
+    pub magic: u32, // must equal ELF_MAGIC
+    pub elf: [u8; 12],
+    pub type_: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+// Program section header
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ProgHeader {
+    pub type_: u32,
+    pub flags: u32,
+    pub off: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// Values for ProgHeader type
+pub const ELF_PROG_LOAD: u32 = 1;
+
+// Flag bits for ProgHeader flags
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub const ELF_PROG_FLAG_READ: u32 = 4;
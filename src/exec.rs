@@ -0,0 +1,280 @@
+use core::cmp::min;
+use core::mem::size_of;
+
+use crate::elf::{
+    ElfHeader, ProgHeader, ELFCLASS64, ELF_MAGIC, ELF_PROG_FLAG_EXEC, ELF_PROG_FLAG_READ,
+    ELF_PROG_FLAG_WRITE, ELF_PROG_LOAD, EM_RISCV,
+};
+use crate::fs::log::{begin_op, end_op};
+use crate::fs::{ilock, iunlockput, namei, readi};
+use crate::mem_utils::slice_cpy;
+use crate::memolayout::TRAPFRAME;
+use crate::params::{MAXARG, NVMA, USERSTACK};
+use crate::proc::{myproc, proc, proc_freepagetable, proc_pagetable};
+use crate::riscv::{PGSIZE, PTE_R, PTE_W, PTE_X};
+use crate::vm::{copyout, uvmalloc, uvmclear, walkaddr, PageTable};
+use crate::vma::{vmaunmapall, Vma};
+use crate::PGROUNDUP;
+
+// Reads bytes of the program image at the given offset into the
+// buffer, returning how many bytes were read.
+pub type ImageReader<'a> = dyn FnMut(&mut [u8], usize) -> usize + 'a;
+
+fn flags2perm(flags: u32) -> u64 {
+    let mut perm = 0;
+    if flags & ELF_PROG_FLAG_READ != 0 {
+        perm |= PTE_R;
+    }
+    if flags & ELF_PROG_FLAG_WRITE != 0 {
+        perm |= PTE_W;
+    }
+    if flags & ELF_PROG_FLAG_EXEC != 0 {
+        perm |= PTE_X;
+    }
+    perm
+}
+
+// Read a plain-old-data struct at off from the image.
+fn read_struct<T: Copy + Default>(read: &mut ImageReader, off: usize) -> Option<T> {
+    let mut v = T::default();
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(&mut v as *mut T as *mut u8, size_of::<T>()) };
+    if read(buf, off) != size_of::<T>() {
+        return None;
+    }
+    Some(v)
+}
+
+pub fn exec(path: &[u8], argv: &[&[u8]]) -> i64 {
+    begin_op();
+
+    let ip = match namei(path) {
+        Some(ip) => ip,
+        None => {
+            end_op();
+            return -1;
+        }
+    };
+    ilock(ip);
+
+    let image = {
+        let mut read = |buf: &mut [u8], off: usize| -> usize {
+            // offsets into an inode are 32 bits.
+            if off
+                .checked_add(buf.len())
+                .is_none_or(|end| end > u32::MAX as usize)
+            {
+                return 0;
+            }
+            let n = readi(
+                ip,
+                false,
+                buf.as_mut_ptr() as u64,
+                off as u32,
+                buf.len() as u32,
+            );
+            if n < 0 {
+                0
+            } else {
+                n as usize
+            }
+        };
+        exec_image(path, &mut read, argv)
+    };
+    iunlockput(ip);
+    end_op();
+
+    match image {
+        Ok((argc, old)) => {
+            old.free();
+            argc
+        }
+        Err(()) => -1,
+    }
+}
+
+// The user memory of a process that exec_image() replaced.
+// Unmapping its memory-mapped regions may write to their files,
+// so the caller frees it once out of any file system transaction.
+pub struct OldImage {
+    pagetable: *mut PageTable,
+    sz: u64,
+    vma: [Vma; NVMA],
+}
+
+impl OldImage {
+    pub fn free(mut self) {
+        vmaunmapall(&mut self.vma, unsafe { &mut *self.pagetable });
+        proc_freepagetable(self.pagetable, self.sz);
+    }
+}
+
+// Replace the current process image with the ELF program that read
+// returns, passing it argv. On failure the old image is left untouched.
+// Returns argc, which ends up in a0, the first argument to main(argc, argv),
+// and the old image for the caller to free.
+pub fn exec_image(
+    path: &[u8],
+    read: &mut ImageReader,
+    argv: &[&[u8]],
+) -> Result<(i64, OldImage), ()> {
+    let p_index = myproc().unwrap();
+    let p = unsafe { &mut proc[p_index] };
+
+    // Check ELF header
+    let elf: ElfHeader = match read_struct(read, 0) {
+        Some(elf) => elf,
+        None => return Err(()),
+    };
+    if elf.magic != ELF_MAGIC || elf.elf[0] != ELFCLASS64 || elf.machine != EM_RISCV {
+        return Err(());
+    }
+
+    let pagetable = proc_pagetable(p).ok_or(())?;
+    let pgtbl = unsafe { &mut *pagetable };
+    let mut sz = 0;
+    match load(pgtbl, &elf, read, &mut sz).and_then(|_| setup_stack(pgtbl, &mut sz, argv)) {
+        Ok(sp) => {
+            // arguments to user main(argc, argv)
+            // argc is returned via the system call return
+            // value, which goes in a0.
+            unsafe { (*p.trapframe).a1 = sp as u64 };
+
+            // Save program name for debugging.
+            let last = match path.iter().rposition(|&c| c == b'/') {
+                Some(i) => &path[i + 1..],
+                None => path,
+            };
+            p.name = [0; 16];
+            slice_cpy(&mut p.name[..15], last);
+
+            // Commit to the user image.
+            let old = OldImage {
+                pagetable: p.pagetable,
+                sz: p.sz,
+                vma: p.vma,
+            };
+            p.vma = [Vma::empty(); NVMA];
+            p.pagetable = pagetable;
+            p.sz = sz as u64;
+            unsafe {
+                (*p.trapframe).epc = elf.entry; // initial program counter = main
+                (*p.trapframe).sp = sp as u64; // initial stack pointer
+            }
+
+            Ok((argv.len() as i64, old))
+        }
+        Err(()) => {
+            proc_freepagetable(pagetable, sz as u64);
+            Err(())
+        }
+    }
+}
+
+// Map and load every PT_LOAD segment, growing sz as we go.
+fn load(
+    pgtbl: &mut PageTable,
+    elf: &ElfHeader,
+    read: &mut ImageReader,
+    sz: &mut usize,
+) -> Result<(), ()> {
+    let mut off = elf.phoff as usize;
+    for _ in 0..elf.phnum {
+        let ph: ProgHeader = read_struct(read, off).ok_or(())?;
+        off += size_of::<ProgHeader>();
+        if ph.type_ != ELF_PROG_LOAD {
+            continue;
+        }
+        if ph.memsz < ph.filesz {
+            return Err(());
+        }
+        // below the trapframe and trampoline, which are
+        // already mapped.
+        match ph.vaddr.checked_add(ph.memsz) {
+            Some(end) if end <= TRAPFRAME as u64 => {}
+            _ => return Err(()),
+        }
+        if ph.vaddr as usize % PGSIZE != 0 {
+            return Err(());
+        }
+        *sz = uvmalloc(
+            pgtbl,
+            *sz,
+            (ph.vaddr + ph.memsz) as usize,
+            flags2perm(ph.flags),
+        )?;
+        loadseg(
+            pgtbl,
+            ph.vaddr as usize,
+            read,
+            ph.off as usize,
+            ph.filesz as usize,
+        )?;
+    }
+    Ok(())
+}
+
+// Load a program segment into pagetable at virtual address va.
+// va must be page-aligned
+// and the pages from va to va+sz must already be mapped.
+fn loadseg(
+    pgtbl: &mut PageTable,
+    va: usize,
+    read: &mut ImageReader,
+    offset: usize,
+    sz: usize,
+) -> Result<(), ()> {
+    for i in (0..sz).step_by(PGSIZE) {
+        let pa = walkaddr(pgtbl, va + i).expect("loadseg: address should exist");
+        let n = min(sz - i, PGSIZE);
+        let dst = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, n) };
+        if read(dst, offset + i) != n {
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+// Allocate USERSTACK pages plus an inaccessible guard page below them
+// after the program, and push the argument strings and the argv[] array.
+// Returns the initial user stack pointer.
+fn setup_stack(pgtbl: &mut PageTable, sz: &mut usize, argv: &[&[u8]]) -> Result<usize, ()> {
+    if argv.len() >= MAXARG {
+        return Err(());
+    }
+
+    *sz = PGROUNDUP!(*sz);
+    *sz = uvmalloc(pgtbl, *sz, *sz + (USERSTACK + 1) * PGSIZE, PTE_W)?;
+    uvmclear(pgtbl, *sz - (USERSTACK + 1) * PGSIZE);
+    let mut sp = *sz;
+    let stackbase = sp - USERSTACK * PGSIZE;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut ustack = [0u64; MAXARG];
+    for (argc, arg) in argv.iter().enumerate() {
+        sp -= arg.len() + 1;
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stackbase {
+            return Err(());
+        }
+        copyout(pgtbl, sp, arg.as_ptr(), arg.len())?;
+        copyout(pgtbl, sp + arg.len(), &0u8 as *const u8, 1)?;
+        ustack[argc] = sp as u64;
+    }
+    let argc = argv.len();
+    ustack[argc] = 0;
+
+    // push the array of argv[] pointers.
+    sp -= (argc + 1) * size_of::<u64>();
+    sp -= sp % 16;
+    if sp < stackbase {
+        return Err(());
+    }
+    copyout(
+        pgtbl,
+        sp,
+        ustack.as_ptr() as *const u8,
+        (argc + 1) * size_of::<u64>(),
+    )?;
+    Ok(sp)
+}
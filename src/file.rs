@@ -0,0 +1,245 @@
+//
+// Support functions for system calls that involve file descriptors.
+//
+
+use core::mem::{align_of, size_of};
+use core::ptr::{addr_of_mut, null_mut};
+
+use crate::fs::log::{begin_op, end_op};
+use crate::fs::{ilock, iput, iunlock, readi, writei, Inode};
+use crate::params::{BSIZE, MAXOPBLOCKS, NDEV, NFILE};
+use crate::pipe::{pipeclose, piperead, pipewrite, Pipe};
+use crate::proc::{myproc, proc};
+use crate::riscv::{PTE_R, PTE_W};
+use crate::slab::KmemCache;
+use crate::spin_lock::SpinLock;
+use crate::vma::vmaprefault;
+
+// open() flags
+#[allow(dead_code)]
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+pub const O_TRUNC: i32 = 0x400;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileType {
+    None,
+    Pipe,
+    Inode,
+    Device,
+}
+
+pub struct File {
+    pub type_: FileType,
+    pub ref_: i32, // reference count
+    pub readable: bool,
+    pub writable: bool,
+    pub pipe: *mut Pipe, // FileType::Pipe
+    pub ip: *mut Inode,  // FileType::Inode and FileType::Device
+    pub off: u32,        // FileType::Inode
+    pub major: i16,      // FileType::Device
+}
+
+// map major device number to device functions.
+// read and write take (user_addr, addr, n) and return
+// the number of bytes transferred, or -1.
+#[derive(Clone, Copy)]
+pub struct Devsw {
+    pub read: Option<fn(bool, u64, i32) -> i32>,
+    pub write: Option<fn(bool, u64, i32) -> i32>,
+}
+
+pub static mut devsw: [Devsw; NDEV] = [Devsw {
+    read: None,
+    write: None,
+}; NDEV];
+
+pub const CONSOLE: usize = 1;
+
+pub static FILE_CACHE: KmemCache = KmemCache::new(
+    "file",
+    size_of::<File>(),
+    align_of::<File>(),
+    Some(file_ctor),
+);
+// Files in use, at most NFILE.
+static mut nfile: usize = 0;
+// protects nfile and the ref counts.
+static ftable_lock: SpinLock = SpinLock::new();
+
+// Files come out of FILE_CACHE unused, and fileclose() hands
+// them back that way.
+fn file_ctor(obj: *mut u8) {
+    unsafe {
+        (obj as *mut File).write(File {
+            type_: FileType::None,
+            ref_: 0,
+            readable: false,
+            writable: false,
+            pipe: null_mut(),
+            ip: null_mut(),
+            off: 0,
+            major: 0,
+        });
+    }
+}
+
+fn device(major: i16) -> Option<&'static Devsw> {
+    if major < 0 || major as usize >= NDEV {
+        return None;
+    }
+    Some(unsafe { &(*addr_of_mut!(devsw))[major as usize] })
+}
+
+// Allocate a file structure.
+pub fn filealloc() -> Option<&'static mut File> {
+    ftable_lock.lock();
+    if unsafe { nfile } == NFILE {
+        ftable_lock.unlock();
+        return None;
+    }
+    let f = match FILE_CACHE.alloc() {
+        Some(f) => f as *mut File,
+        None => {
+            ftable_lock.unlock();
+            return None;
+        }
+    };
+    unsafe {
+        nfile += 1;
+        (*f).ref_ = 1;
+    }
+    ftable_lock.unlock();
+    Some(unsafe { &mut *f })
+}
+
+// Increment ref count for file f.
+pub fn filedup(f: &mut File) -> &'static mut File {
+    ftable_lock.lock();
+    if f.ref_ < 1 {
+        panic!("filedup");
+    }
+    f.ref_ += 1;
+    ftable_lock.unlock();
+    unsafe { &mut *(f as *mut File) }
+}
+
+// Close file f.  (Decrement ref count, close when reaches 0.)
+pub fn fileclose(f: &mut File) {
+    ftable_lock.lock();
+    if f.ref_ < 1 {
+        panic!("fileclose");
+    }
+    f.ref_ -= 1;
+    if f.ref_ > 0 {
+        ftable_lock.unlock();
+        return;
+    }
+    let type_ = f.type_;
+    let (pipe, ip, writable) = (f.pipe, f.ip, f.writable);
+    // back to the cache as file_ctor() made it.
+    file_ctor(f as *mut File as *mut u8);
+    FILE_CACHE.free(f as *mut File as *mut u8);
+    unsafe { nfile -= 1 };
+    ftable_lock.unlock();
+
+    match type_ {
+        FileType::Pipe => pipeclose(unsafe { &mut *pipe }, writable),
+        FileType::Inode | FileType::Device => {
+            begin_op();
+            iput(unsafe { &mut *ip });
+            end_op();
+        }
+        FileType::None => {}
+    }
+}
+
+// Read in the pages of file mappings that fileread() or filewrite()
+// is about to copy to or from: pipes, devices and inodes do the
+// copying with locks held, when copyout() and copyin() can't.
+fn prefault(addr: u64, n: i32, perm: u64) {
+    if n > 0 {
+        let p = unsafe { &proc[myproc().unwrap()] };
+        vmaprefault(p, addr as usize, n as usize, perm);
+    }
+}
+
+// Read from file f.
+// addr is a user virtual address.
+pub fn fileread(f: &mut File, addr: u64, n: i32) -> i32 {
+    if !f.readable {
+        return -1;
+    }
+    prefault(addr, n, PTE_W);
+
+    match f.type_ {
+        FileType::Pipe => piperead(f.pipe, addr, n),
+        FileType::Device => match device(f.major).and_then(|d| d.read) {
+            Some(read) => read(true, addr, n),
+            None => -1,
+        },
+        FileType::Inode => {
+            let ip = unsafe { &mut *f.ip };
+            ilock(ip);
+            let r = readi(ip, true, addr, f.off, n as u32);
+            if r > 0 {
+                f.off += r as u32;
+            }
+            iunlock(ip);
+            r
+        }
+        FileType::None => panic!("fileread"),
+    }
+}
+
+// Write to file f.
+// addr is a user virtual address.
+pub fn filewrite(f: &mut File, addr: u64, n: i32) -> i32 {
+    if !f.writable {
+        return -1;
+    }
+    prefault(addr, n, PTE_R);
+
+    match f.type_ {
+        FileType::Pipe => pipewrite(f.pipe, addr, n),
+        FileType::Device => match device(f.major).and_then(|d| d.write) {
+            Some(write) => write(true, addr, n),
+            None => -1,
+        },
+        FileType::Inode => {
+            // write a few blocks at a time to avoid exceeding
+            // the maximum log transaction size, including
+            // i-node, indirect block, allocation blocks,
+            // and 2 blocks of slop for non-aligned writes.
+            let max = (((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE) as i32;
+            let ip = unsafe { &mut *f.ip };
+            let mut i = 0;
+            while i < n {
+                let n1 = core::cmp::min(n - i, max);
+
+                begin_op();
+                ilock(ip);
+                let r = writei(ip, true, addr + i as u64, f.off, n1 as u32);
+                if r > 0 {
+                    f.off += r as u32;
+                }
+                iunlock(ip);
+                end_op();
+
+                if r != n1 {
+                    // error from writei
+                    break;
+                }
+                i += r;
+            }
+            if i == n {
+                n
+            } else {
+                -1
+            }
+        }
+        FileType::None => panic!("filewrite"),
+    }
+}
@@ -0,0 +1,76 @@
+// On-disk file system format.
+// Both the kernel and the mkfs tool use this file.
+
+use core::mem::size_of;
+
+use crate::params::BSIZE;
+
+pub const ROOTINO: u32 = 1; // root i-number
+
+// Disk layout:
+// [ boot block | super block | log | inode blocks |
+//                                          free bit map | data blocks]
+//
+// mkfs computes the super block and builds an initial file system. The
+// super block describes the disk layout:
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SuperBlock {
+    pub magic: u32,      // Must be FSMAGIC
+    pub size: u32,       // Size of file system image (blocks)
+    pub nblocks: u32,    // Number of data blocks
+    pub ninodes: u32,    // Number of inodes.
+    pub nlog: u32,       // Number of log blocks
+    pub logstart: u32,   // Block number of first log block
+    pub inodestart: u32, // Block number of first inode block
+    pub bmapstart: u32,  // Block number of first free map block
+}
+
+pub const FSMAGIC: u32 = 0x10203040;
+
+pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+
+// On-disk inode structure
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Dinode {
+    pub type_: i16,                // File type
+    pub major: i16,                // Major device number (T_DEVICE only)
+    pub minor: i16,                // Minor device number (T_DEVICE only)
+    pub nlink: i16,                // Number of links to inode in file system
+    pub size: u32,                 // Size of file (bytes)
+    pub addrs: [u32; NDIRECT + 1], // Data block addresses
+}
+
+// Inodes per block.
+pub const IPB: u32 = (BSIZE / size_of::<Dinode>()) as u32;
+
+// Block containing inode i
+pub fn iblock(i: u32, sb: &SuperBlock) -> u32 {
+    i / IPB + sb.inodestart
+}
+
+// Bitmap bits per block
+pub const BPB: u32 = (BSIZE * 8) as u32;
+
+// Block of free map containing bit for block b
+pub fn bblock(b: u32, sb: &SuperBlock) -> u32 {
+    b / BPB + sb.bmapstart
+}
+
+// Directory is a file containing a sequence of dirent structures.
+pub const DIRSIZ: usize = 14;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+// Inode types
+pub const T_DIR: i16 = 1; // Directory
+pub const T_FILE: i16 = 2; // File
+pub const T_DEVICE: i16 = 3; // Device
@@ -0,0 +1,232 @@
+// Simple logging that allows concurrent FS system calls.
+//
+// A log transaction contains the updates of multiple FS system
+// calls. The logging system only commits when there are
+// no FS system calls active. Thus there is never
+// any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// A system call should call begin_op()/end_op() to mark
+// its start and end. Usually begin_op() just increments
+// the count of in-progress FS system calls and returns.
+// But if it thinks the log is close to running out, it
+// sleeps until the last outstanding end_op() commits.
+//
+// The log is a physical re-do log containing disk blocks.
+// The on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// Log appends are synchronous.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, read_unaligned, write_unaligned};
+
+use super::layout::SuperBlock;
+use crate::bio::{bpin, bread, brelse, bunpin, bwrite};
+use crate::params::{BSIZE, LOGSIZE, MAXOPBLOCKS};
+use crate::proc::{sleep, wakeup};
+use crate::spin_lock::SpinLock;
+use crate::virtio::virtio_blk::DiskBuffer;
+
+// Contents of the header block, used for both the on-disk header block
+// and to keep track in memory of logged block# before commit.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+const _: () = assert!(size_of::<LogHeader>() < BSIZE, "initlog: too big logheader");
+
+struct Log {
+    start: u32,
+    size: u32,
+    outstanding: u32, // how many FS sys calls are executing.
+    committing: bool, // in commit(), please wait.
+    dev: u32,
+    lh: LogHeader,
+}
+
+static mut log: Log = Log {
+    start: 0,
+    size: 0,
+    outstanding: 0,
+    committing: false,
+    dev: 0,
+    lh: LogHeader {
+        n: 0,
+        block: [0; LOGSIZE],
+    },
+};
+static log_lock: SpinLock = SpinLock::new();
+
+fn the_log() -> &'static mut Log {
+    unsafe { &mut *addr_of_mut!(log) }
+}
+
+// sleep()/wakeup() channel for processes waiting on the log.
+fn log_chan() -> usize {
+    addr_of!(log) as usize
+}
+
+pub fn initlog(dev: u32, sb: &SuperBlock) {
+    let lg = the_log();
+    lg.start = sb.logstart;
+    lg.size = sb.nlog;
+    lg.dev = dev;
+    recover_from_log();
+}
+
+// Copy committed blocks from log to their home location
+fn install_trans(recovering: bool) {
+    let lg = the_log();
+    for tail in 0..lg.lh.n {
+        let lbuf = bread(lg.dev, lg.start + tail + 1); // read log block
+        let dbuf = bread(lg.dev, lg.lh.block[tail as usize]); // read dst
+        dbuf.data.copy_from_slice(&lbuf.data); // copy block to dst
+        bwrite(dbuf); // write dst to disk
+        if !recovering {
+            bunpin(dbuf);
+        }
+        brelse(lbuf);
+        brelse(dbuf);
+    }
+}
+
+// Read the log header from disk into the in-memory log header
+fn read_head() {
+    let lg = the_log();
+    let buf = bread(lg.dev, lg.start);
+    lg.lh = unsafe { read_unaligned(buf.data.as_ptr() as *const LogHeader) };
+    brelse(buf);
+}
+
+// Write in-memory log header to disk.
+// This is the true point at which the
+// current transaction commits.
+fn write_head() {
+    let lg = the_log();
+    let buf = bread(lg.dev, lg.start);
+    unsafe { write_unaligned(buf.data.as_mut_ptr() as *mut LogHeader, lg.lh) };
+    bwrite(buf);
+    brelse(buf);
+}
+
+fn recover_from_log() {
+    read_head();
+    install_trans(true); // if committed, copy from log to disk
+    the_log().lh.n = 0;
+    write_head(); // clear the log
+}
+
+// called at the start of each FS system call.
+pub fn begin_op() {
+    log_lock.lock();
+    loop {
+        let lg = the_log();
+        if lg.committing {
+            sleep(log_chan(), &log_lock);
+        } else if lg.lh.n as usize + (lg.outstanding as usize + 1) * MAXOPBLOCKS > LOGSIZE {
+            // this op might exhaust log space; wait for commit.
+            sleep(log_chan(), &log_lock);
+        } else {
+            lg.outstanding += 1;
+            log_lock.unlock();
+            break;
+        }
+    }
+}
+
+// called at the end of each FS system call.
+// commits if this was the last outstanding operation.
+pub fn end_op() {
+    let mut do_commit = false;
+
+    log_lock.lock();
+    let lg = the_log();
+    lg.outstanding -= 1;
+    if lg.committing {
+        panic!("log.committing");
+    }
+    if lg.outstanding == 0 {
+        do_commit = true;
+        lg.committing = true;
+    } else {
+        // begin_op() may be waiting for log space,
+        // and decrementing log.outstanding has decreased
+        // the amount of reserved space.
+        wakeup(log_chan());
+    }
+    log_lock.unlock();
+
+    if do_commit {
+        // call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        commit();
+        log_lock.lock();
+        the_log().committing = false;
+        wakeup(log_chan());
+        log_lock.unlock();
+    }
+}
+
+// Copy modified blocks from cache to log.
+fn write_log() {
+    let lg = the_log();
+    for tail in 0..lg.lh.n {
+        let to = bread(lg.dev, lg.start + tail + 1); // log block
+        let from = bread(lg.dev, lg.lh.block[tail as usize]); // cache block
+        to.data.copy_from_slice(&from.data);
+        bwrite(to); // write the log
+        brelse(from);
+        brelse(to);
+    }
+}
+
+fn commit() {
+    if the_log().lh.n > 0 {
+        write_log(); // Write modified blocks from cache to log
+        write_head(); // Write header to disk -- the real commit
+        install_trans(false); // Now install writes to home locations
+        the_log().lh.n = 0;
+        write_head(); // Erase the transaction from the log
+    }
+}
+
+// Caller has modified b.data and is done with the buffer.
+// Record the block number and pin in the cache by increasing refcnt.
+// commit()/write_log() will do the disk write.
+//
+// log_write() replaces bwrite(); a typical use is:
+//   bp = bread(...)
+//   modify bp.data[]
+//   log_write(bp)
+//   brelse(bp)
+pub fn log_write(b: &mut DiskBuffer) {
+    log_lock.lock();
+    let lg = the_log();
+    if lg.lh.n as usize >= LOGSIZE || lg.lh.n + 1 >= lg.size {
+        panic!("too big a transaction");
+    }
+    if lg.outstanding < 1 {
+        panic!("log_write outside of trans");
+    }
+
+    let n = lg.lh.n as usize;
+    // log absorption
+    let i = lg.lh.block[..n]
+        .iter()
+        .position(|&blockno| blockno == b.blockno)
+        .unwrap_or(n);
+    lg.lh.block[i] = b.blockno;
+    if i == n {
+        // Add new block to log?
+        bpin(b);
+        lg.lh.n += 1;
+    }
+    log_lock.unlock();
+}
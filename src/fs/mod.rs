@@ -0,0 +1,690 @@
+// File system implementation.  Five layers:
+//   + Blocks: allocator for raw disk blocks.
+//   + Log: crash recovery for multi-step updates.
+//   + Files: inode allocator, reading, writing, metadata.
+//   + Directories: inode with special contents (list of other inodes!)
+//   + Names: paths like /usr/rtm/xv6/fs.c for convenient naming.
+//
+// This file contains the low-level file system manipulation
+// routines.  The (higher-level) system call implementations
+// are in sysfile.rs.
+
+use core::cmp::min;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::{addr_of, addr_of_mut, read_unaligned, write_unaligned};
+
+use crate::bio::{bread, brelse};
+use crate::params::{BSIZE, NINODE, ROOTDEV};
+use crate::println;
+use crate::proc::{either_copyin, either_copyout, myproc, proc};
+use crate::sleep_lock::SleepLock;
+use crate::spin_lock::SpinLock;
+use crate::virtio::virtio_blk::DiskBuffer;
+use layout::*;
+use log::{initlog, log_write};
+
+pub mod layout;
+pub mod log;
+
+// there should be one superblock per disk device, but we run with
+// only one device
+static mut sb: SuperBlock = SuperBlock {
+    magic: 0,
+    size: 0,
+    nblocks: 0,
+    ninodes: 0,
+    nlog: 0,
+    logstart: 0,
+    inodestart: 0,
+    bmapstart: 0,
+};
+
+fn superblock() -> &'static SuperBlock {
+    unsafe { &*addr_of!(sb) }
+}
+
+// Read the super block.
+fn readsb(dev: u32) {
+    let bp = bread(dev, 1);
+    unsafe {
+        *addr_of_mut!(sb) = read_unaligned(bp.data.as_ptr() as *const SuperBlock);
+    }
+    brelse(bp);
+}
+
+// Init fs
+pub fn fsinit(dev: u32) {
+    readsb(dev);
+    if superblock().magic != FSMAGIC {
+        panic!("invalid file system");
+    }
+    initlog(dev, superblock());
+}
+
+// Zero a block.
+fn bzero(dev: u32, bno: u32) {
+    let bp = bread(dev, bno);
+    bp.data.fill(0);
+    log_write(bp);
+    brelse(bp);
+}
+
+// Blocks.
+
+// Allocate a zeroed disk block.
+// returns None if out of disk space.
+fn balloc(dev: u32) -> Option<u32> {
+    let sb_ref = superblock();
+    for b in (0..sb_ref.size).step_by(BPB as usize) {
+        let bp = bread(dev, bblock(b, sb_ref));
+        let mut bi = 0;
+        while bi < BPB && b + bi < sb_ref.size {
+            let m = 1u8 << (bi % 8);
+            if bp.data[(bi / 8) as usize] & m == 0 {
+                // Is block free?
+                bp.data[(bi / 8) as usize] |= m; // Mark block in use.
+                log_write(bp);
+                brelse(bp);
+                bzero(dev, b + bi);
+                return Some(b + bi);
+            }
+            bi += 1;
+        }
+        brelse(bp);
+    }
+    println!("balloc: out of blocks");
+    None
+}
+
+// Free a disk block.
+fn bfree(dev: u32, b: u32) {
+    let bp = bread(dev, bblock(b, superblock()));
+    let bi = b % BPB;
+    let m = 1u8 << (bi % 8);
+    if bp.data[(bi / 8) as usize] & m == 0 {
+        panic!("freeing free block");
+    }
+    bp.data[(bi / 8) as usize] &= !m;
+    log_write(bp);
+    brelse(bp);
+}
+
+// Inodes.
+//
+// An inode describes a single unnamed file.
+// The inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// The inodes are laid out sequentially on disk at block
+// sb.inodestart. Each inode has a number, indicating its
+// position on the disk.
+//
+// The kernel keeps a table of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. The in-memory
+// inodes include book-keeping information that is
+// not stored on disk: ref and valid.
+//
+// An inode and its in-memory representation go through a
+// sequence of states before they can be used by the
+// rest of the file system code.
+//
+// * Allocation: an inode is allocated if its type (on disk)
+//   is non-zero. ialloc() allocates, and iput() frees if
+//   the reference and link counts have fallen to zero.
+//
+// * Referencing in table: an entry in the inode table
+//   is free if ref is zero. Otherwise ref tracks
+//   the number of in-memory pointers to the entry (open
+//   files and current directories). iget() finds or
+//   creates a table entry and increments its ref; iput()
+//   decrements ref.
+//
+// * Valid: the information (type, size, &c) in an inode
+//   table entry is only correct when valid is true.
+//   ilock() reads the inode from
+//   the disk and sets valid, while iput() clears
+//   valid if ref has fallen to zero.
+//
+// * Locked: file system code may only examine and modify
+//   the information in an inode and its content if it
+//   has first locked the inode.
+//
+// Thus a typical sequence is:
+//   ip = iget(dev, inum)
+//   ilock(ip)
+//   ... examine and modify ip.xxx ...
+//   iunlock(ip)
+//   iput(ip)
+//
+// ilock() is separate from iget() so that system calls can
+// get a long-term reference to an inode (as for an open file)
+// and only lock it for short periods (e.g., in read()).
+// The separation also helps avoid deadlock and races during
+// pathname lookup. iget() increments ref so that the inode
+// stays in the table and pointers to it remain valid.
+//
+// Many internal file system functions expect the caller to
+// have locked the inodes involved; this lets callers create
+// multi-step atomic operations.
+//
+// itable_lock protects the allocation of itable
+// entries. Since ref indicates whether an entry is free,
+// and dev and inum indicate which i-node an entry
+// holds, one must hold itable_lock while using any
+// of those fields.
+//
+// An ip.lock sleep-lock protects all ip fields other than ref,
+// dev, and inum.  One must hold ip.lock in order to
+// read or write that inode's ip.valid, ip.size, ip.type_, &c.
+
+// in-memory copy of an inode
+pub struct Inode {
+    pub dev: u32,        // Device number
+    pub inum: u32,       // Inode number
+    pub ref_: i32,       // Reference count
+    pub lock: SleepLock, // protects everything below here
+    pub valid: bool,     // inode has been read from disk?
+
+    pub type_: i16, // copy of disk inode
+    pub major: i16,
+    pub minor: i16,
+    pub nlink: i16,
+    pub size: u32,
+    pub addrs: [u32; NDIRECT + 1],
+}
+
+static mut itable: [Inode; NINODE] = unsafe { MaybeUninit::zeroed().assume_init() };
+static itable_lock: SpinLock = SpinLock::new();
+
+// Where inode inum lives inside the buffer of its inode block.
+fn dinode_ptr(bp: &mut DiskBuffer, inum: u32) -> *mut Dinode {
+    unsafe { (bp.data.as_mut_ptr() as *mut Dinode).add((inum % IPB) as usize) }
+}
+
+// Allocate an inode on device dev.
+// Mark it as allocated by  giving it type type_.
+// Returns an unlocked but allocated and referenced inode,
+// or None if there is no free inode.
+pub fn ialloc(dev: u32, type_: i16) -> Option<&'static mut Inode> {
+    let sb_ref = superblock();
+    for inum in 1..sb_ref.ninodes {
+        let bp = bread(dev, iblock(inum, sb_ref));
+        let dip = dinode_ptr(bp, inum);
+        let mut di = unsafe { read_unaligned(dip) };
+        if di.type_ == 0 {
+            // a free inode
+            di = Dinode::default();
+            di.type_ = type_;
+            unsafe { write_unaligned(dip, di) };
+            log_write(bp); // mark it allocated on the disk
+            brelse(bp);
+            return Some(iget(dev, inum));
+        }
+        brelse(bp);
+    }
+    println!("ialloc: no inodes");
+    None
+}
+
+// Copy a modified in-memory inode to disk.
+// Must be called after every change to an ip.xxx field
+// that lives on disk.
+// Caller must hold ip.lock.
+pub fn iupdate(ip: &mut Inode) {
+    let bp = bread(ip.dev, iblock(ip.inum, superblock()));
+    let dip = dinode_ptr(bp, ip.inum);
+    let di = Dinode {
+        type_: ip.type_,
+        major: ip.major,
+        minor: ip.minor,
+        nlink: ip.nlink,
+        size: ip.size,
+        addrs: ip.addrs,
+    };
+    unsafe { write_unaligned(dip, di) };
+    log_write(bp);
+    brelse(bp);
+}
+
+// Find the inode with number inum on device dev
+// and return the in-memory copy. Does not lock
+// the inode and does not read it from disk.
+pub fn iget(dev: u32, inum: u32) -> &'static mut Inode {
+    itable_lock.lock();
+    let table = unsafe { &mut *addr_of_mut!(itable) };
+
+    // Is the inode already in the table?
+    let mut empty: Option<usize> = None;
+    for i in 0..NINODE {
+        let ip = &table[i];
+        if ip.ref_ > 0 && ip.dev == dev && ip.inum == inum {
+            table[i].ref_ += 1;
+            itable_lock.unlock();
+            return &mut table[i];
+        }
+        if empty.is_none() && ip.ref_ == 0 {
+            // Remember empty slot.
+            empty = Some(i);
+        }
+    }
+
+    // Recycle an inode entry.
+    let ip = &mut table[empty.expect("iget: no inodes")];
+    ip.dev = dev;
+    ip.inum = inum;
+    ip.ref_ = 1;
+    ip.valid = false;
+    itable_lock.unlock();
+    ip
+}
+
+// Increment reference count for ip.
+// Returns ip to enable ip = idup(ip1) idiom.
+pub fn idup(ip: &mut Inode) -> &'static mut Inode {
+    itable_lock.lock();
+    ip.ref_ += 1;
+    itable_lock.unlock();
+    unsafe { &mut *(ip as *mut Inode) }
+}
+
+// Lock the given inode.
+// Reads the inode from disk if necessary.
+pub fn ilock(ip: &mut Inode) {
+    if ip.ref_ < 1 {
+        panic!("ilock");
+    }
+
+    ip.lock.lock();
+
+    if !ip.valid {
+        let bp = bread(ip.dev, iblock(ip.inum, superblock()));
+        let di = unsafe { read_unaligned(dinode_ptr(bp, ip.inum)) };
+        ip.type_ = di.type_;
+        ip.major = di.major;
+        ip.minor = di.minor;
+        ip.nlink = di.nlink;
+        ip.size = di.size;
+        ip.addrs = di.addrs;
+        brelse(bp);
+        ip.valid = true;
+        if ip.type_ == 0 {
+            panic!("ilock: no type");
+        }
+    }
+}
+
+// Unlock the given inode.
+pub fn iunlock(ip: &mut Inode) {
+    if !ip.lock.holding() || ip.ref_ < 1 {
+        panic!("iunlock");
+    }
+
+    ip.lock.unlock();
+}
+
+// Drop a reference to an in-memory inode.
+// If that was the last reference, the inode table entry can
+// be recycled.
+// If that was the last reference and the inode has no links
+// to it, free the inode (and its content) on disk.
+pub fn iput(ip: &mut Inode) {
+    itable_lock.lock();
+
+    if ip.ref_ == 1 && ip.valid && ip.nlink == 0 {
+        // inode has no links and no other references: truncate and free.
+
+        // ip.ref_ == 1 means no other process can have ip locked,
+        // so this lock() won't block (or deadlock).
+        ip.lock.lock();
+
+        itable_lock.unlock();
+
+        itrunc(ip);
+        ip.type_ = 0;
+        iupdate(ip);
+        ip.valid = false;
+
+        ip.lock.unlock();
+
+        itable_lock.lock();
+    }
+
+    ip.ref_ -= 1;
+    itable_lock.unlock();
+}
+
+// Common idiom: unlock, then put.
+pub fn iunlockput(ip: &mut Inode) {
+    iunlock(ip);
+    iput(ip);
+}
+
+// Inode content
+//
+// The content (data) associated with each inode is stored
+// in blocks on the disk. The first NDIRECT block numbers
+// are listed in ip.addrs[].  The next NINDIRECT blocks are
+// listed in block ip.addrs[NDIRECT].
+
+// Return the disk block address of the nth block in inode ip.
+// If there is no such block, bmap allocates one.
+// returns None if out of disk space.
+fn bmap(ip: &mut Inode, bn: usize) -> Option<u32> {
+    if bn < NDIRECT {
+        if ip.addrs[bn] == 0 {
+            ip.addrs[bn] = balloc(ip.dev)?;
+        }
+        return Some(ip.addrs[bn]);
+    }
+    let bn = bn - NDIRECT;
+
+    if bn < NINDIRECT {
+        // Load indirect block, allocating if necessary.
+        if ip.addrs[NDIRECT] == 0 {
+            ip.addrs[NDIRECT] = balloc(ip.dev)?;
+        }
+        let bp = bread(ip.dev, ip.addrs[NDIRECT]);
+        let a = unsafe { (bp.data.as_mut_ptr() as *mut u32).add(bn) };
+        let mut addr = unsafe { read_unaligned(a) };
+        if addr == 0 {
+            match balloc(ip.dev) {
+                Some(new) => {
+                    addr = new;
+                    unsafe { write_unaligned(a, addr) };
+                    log_write(bp);
+                }
+                None => {
+                    brelse(bp);
+                    return None;
+                }
+            }
+        }
+        brelse(bp);
+        return Some(addr);
+    }
+
+    panic!("bmap: out of range");
+}
+
+// Truncate inode (discard contents).
+// Caller must hold ip.lock.
+pub fn itrunc(ip: &mut Inode) {
+    for i in 0..NDIRECT {
+        if ip.addrs[i] != 0 {
+            bfree(ip.dev, ip.addrs[i]);
+            ip.addrs[i] = 0;
+        }
+    }
+
+    if ip.addrs[NDIRECT] != 0 {
+        let bp = bread(ip.dev, ip.addrs[NDIRECT]);
+        let a = bp.data.as_ptr() as *const u32;
+        for j in 0..NINDIRECT {
+            let addr = unsafe { read_unaligned(a.add(j)) };
+            if addr != 0 {
+                bfree(ip.dev, addr);
+            }
+        }
+        brelse(bp);
+        bfree(ip.dev, ip.addrs[NDIRECT]);
+        ip.addrs[NDIRECT] = 0;
+    }
+
+    ip.size = 0;
+    iupdate(ip);
+}
+
+// Read data from inode.
+// Caller must hold ip.lock.
+// If user_dst is true, then dst is a user virtual address;
+// otherwise, dst is a kernel address.
+// Returns the number of bytes read, or -1 if copying out failed.
+pub fn readi(ip: &mut Inode, user_dst: bool, dst: u64, off: u32, n: u32) -> i32 {
+    if off > ip.size || off.checked_add(n).is_none() {
+        return 0;
+    }
+    let n = min(n, ip.size - off);
+
+    let mut tot = 0;
+    let mut off = off;
+    let mut dst = dst;
+    while tot < n {
+        let addr = match bmap(ip, off as usize / BSIZE) {
+            Some(addr) => addr,
+            None => break,
+        };
+        let bp = bread(ip.dev, addr);
+        let m = min(n - tot, (BSIZE - off as usize % BSIZE) as u32);
+        let src = unsafe { bp.data.as_ptr().add(off as usize % BSIZE) };
+        if either_copyout(user_dst, dst, src, m as usize).is_err() {
+            brelse(bp);
+            return -1;
+        }
+        brelse(bp);
+        tot += m;
+        off += m;
+        dst += m as u64;
+    }
+    tot as i32
+}
+
+// Write data to inode.
+// Caller must hold ip.lock.
+// If user_src is true, then src is a user virtual address;
+// otherwise, src is a kernel address.
+// Returns the number of bytes successfully written.
+// If the return value is less than the requested n,
+// there was an error of some kind.
+pub fn writei(ip: &mut Inode, user_src: bool, src: u64, off: u32, n: u32) -> i32 {
+    if off > ip.size || off.checked_add(n).is_none() {
+        return -1;
+    }
+    if off as usize + n as usize > MAXFILE * BSIZE {
+        return -1;
+    }
+
+    let mut tot = 0;
+    let mut off = off;
+    let mut src = src;
+    while tot < n {
+        let addr = match bmap(ip, off as usize / BSIZE) {
+            Some(addr) => addr,
+            None => break,
+        };
+        let bp = bread(ip.dev, addr);
+        let m = min(n - tot, (BSIZE - off as usize % BSIZE) as u32);
+        let dst = unsafe { bp.data.as_mut_ptr().add(off as usize % BSIZE) };
+        if either_copyin(dst, user_src, src, m as usize).is_err() {
+            brelse(bp);
+            break;
+        }
+        log_write(bp);
+        brelse(bp);
+        tot += m;
+        off += m;
+        src += m as u64;
+    }
+
+    if off > ip.size {
+        ip.size = off;
+    }
+
+    // write the i-node back to disk even if the size didn't change
+    // because the loop above might have called bmap() and added a new
+    // block to ip.addrs[].
+    iupdate(ip);
+
+    tot as i32
+}
+
+// Directories
+
+// Compare two directory entry names, like strncmp(s, t, DIRSIZ) == 0.
+pub fn namecmp(s: &[u8], t: &[u8]) -> bool {
+    for i in 0..DIRSIZ {
+        let a = s.get(i).copied().unwrap_or(0);
+        let b = t.get(i).copied().unwrap_or(0);
+        if a != b {
+            return false;
+        }
+        if a == 0 {
+            break;
+        }
+    }
+    true
+}
+
+// Look for a directory entry in a directory.
+// If found, set *poff to byte offset of entry.
+pub fn dirlookup(
+    dp: &mut Inode,
+    name: &[u8],
+    poff: Option<&mut u32>,
+) -> Option<&'static mut Inode> {
+    if dp.type_ != T_DIR {
+        panic!("dirlookup not DIR");
+    }
+
+    let sz = size_of::<Dirent>() as u32;
+    for off in (0..dp.size).step_by(sz as usize) {
+        let mut de = Dirent::default();
+        if readi(dp, false, &mut de as *mut Dirent as u64, off, sz) != sz as i32 {
+            panic!("dirlookup read");
+        }
+        if de.inum == 0 {
+            continue;
+        }
+        if namecmp(name, &de.name) {
+            // entry matches path element
+            if let Some(poff) = poff {
+                *poff = off;
+            }
+            return Some(iget(dp.dev, de.inum as u32));
+        }
+    }
+
+    None
+}
+
+// Write a new directory entry (name, inum) into the directory dp.
+// Returns Err if the name is already present or the write failed.
+pub fn dirlink(dp: &mut Inode, name: &[u8], inum: u32) -> Result<(), ()> {
+    // Check that name is not present.
+    if let Some(ip) = dirlookup(dp, name, None) {
+        iput(ip);
+        return Err(());
+    }
+
+    // Look for an empty dirent.
+    let sz = size_of::<Dirent>() as u32;
+    let mut de = Dirent::default();
+    let mut off = 0;
+    while off < dp.size {
+        if readi(dp, false, &mut de as *mut Dirent as u64, off, sz) != sz as i32 {
+            panic!("dirlink read");
+        }
+        if de.inum == 0 {
+            break;
+        }
+        off += sz;
+    }
+
+    de.name = [0; DIRSIZ];
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    let len = min(len, DIRSIZ);
+    de.name[..len].copy_from_slice(&name[..len]);
+    de.inum = inum as u16;
+    if writei(dp, false, &de as *const Dirent as u64, off, sz) != sz as i32 {
+        return Err(());
+    }
+    Ok(())
+}
+
+// Paths
+
+// Copy the next path element from path into name.
+// Return the rest of the path following the copied one,
+// with leading slashes removed, or None if there is no
+// element left to copy.
+// The caller can check whether the rest is empty to see
+// if the name is the last one.
+//
+// Examples:
+//   skipelem("a/bb/c", name) = "bb/c", setting name = "a"
+//   skipelem("///a//bb", name) = "bb", setting name = "a"
+//   skipelem("a", name) = "", setting name = "a"
+//   skipelem("", name) = skipelem("////", name) = None
+//
+fn skipelem<'a>(path: &'a [u8], name: &mut [u8; DIRSIZ]) -> Option<&'a [u8]> {
+    // a path ends at its first nul, if it has one
+    let end = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+    let mut path = &path[..end];
+
+    while path.first() == Some(&b'/') {
+        path = &path[1..];
+    }
+    if path.is_empty() {
+        return None;
+    }
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+    *name = [0; DIRSIZ];
+    let n = min(len, DIRSIZ);
+    name[..n].copy_from_slice(&path[..n]);
+    path = &path[len..];
+    while path.first() == Some(&b'/') {
+        path = &path[1..];
+    }
+    Some(path)
+}
+
+// Look up and return the inode for a path name.
+// If parent is true, return the inode for the parent and copy the final
+// path element into name, which must have room for DIRSIZ bytes.
+fn namex(path: &[u8], nameiparent: bool, name: &mut [u8; DIRSIZ]) -> Option<&'static mut Inode> {
+    let mut ip = if path.first() == Some(&b'/') {
+        iget(ROOTDEV as u32, ROOTINO)
+    } else {
+        let p = unsafe { &proc[myproc().unwrap()] };
+        idup(unsafe { &mut *p.cwd })
+    };
+
+    let mut path = path;
+    while let Some(rest) = skipelem(path, name) {
+        path = rest;
+        ilock(ip);
+        if ip.type_ != T_DIR {
+            iunlockput(ip);
+            return None;
+        }
+        if nameiparent && path.is_empty() {
+            // Stop one level early.
+            iunlock(ip);
+            return Some(ip);
+        }
+        let next = match dirlookup(ip, name, None) {
+            Some(next) => next,
+            None => {
+                iunlockput(ip);
+                return None;
+            }
+        };
+        iunlockput(ip);
+        ip = next;
+    }
+    if nameiparent {
+        iput(ip);
+        return None;
+    }
+    Some(ip)
+}
+
+pub fn namei(path: &[u8]) -> Option<&'static mut Inode> {
+    let mut name = [0u8; DIRSIZ];
+    namex(path, false, &mut name)
+}
+
+pub fn nameiparent(path: &[u8], name: &mut [u8; DIRSIZ]) -> Option<&'static mut Inode> {
+    namex(path, true, name)
+}
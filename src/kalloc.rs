@@ -0,0 +1,163 @@
+// Physical memory allocator, for user processes,
+// kernel stacks, page-table pages,
+// and pipe buffers. Allocates whole 4096-byte pages.
+//
+// The first KHEAPSIZE bytes after the kernel are set aside
+// for the global allocator behind Rust's alloc, and the next
+// buddy::POOLSIZE bytes for the buddy allocator; every page
+// after that, up to PHYSTOP, is handed out by kalloc().
+//
+// Pages from kalloc() are reference counted so that copy-on-write
+// fork can share them: kalloc() returns a page with one reference,
+// kdup() adds one, and kfree() drops one, freeing the page when
+// none are left.
+
+use core::ptr::{addr_of_mut, null_mut};
+
+use crate::buddy::{buddy_dump, buddyinit, POOLSIZE};
+use crate::file::FILE_CACHE;
+use crate::memolayout::{get_kernel_end, KERNELBASE, PHYSTOP};
+use crate::params::KHEAPSIZE;
+use crate::pipe::PIPE_CACHE;
+use crate::proc::TRAPFRAME_CACHE;
+use crate::riscv::PGSIZE;
+use crate::slab::slab_dump;
+use crate::spin_lock::SpinLock;
+use crate::virtio::virtio_blk::VIRTQ_REQ_CACHE;
+use crate::{ALLOCATOR, PGROUNDUP};
+
+struct Run {
+    next: *mut Run,
+}
+
+const NPAGES: usize = (PHYSTOP - KERNELBASE) / PGSIZE;
+
+struct Kmem {
+    freelist: *mut Run,
+    start: usize, // first page kalloc() may hand out
+    // references to each physical page, indexed by pa2index().
+    refcnt: [u16; NPAGES],
+}
+
+static mut kmem: Kmem = Kmem {
+    freelist: null_mut(),
+    start: 0,
+    refcnt: [0; NPAGES],
+};
+static kmem_lock: SpinLock = SpinLock::new();
+
+fn the_kmem() -> &'static mut Kmem {
+    unsafe { &mut *addr_of_mut!(kmem) }
+}
+
+pub fn kinit() {
+    let heap_start = PGROUNDUP!(get_kernel_end());
+    let heap_end = heap_start + KHEAPSIZE;
+    let pool_end = heap_end + POOLSIZE;
+    if pool_end >= PHYSTOP {
+        panic!("kinit: heap too big");
+    }
+    unsafe {
+        ALLOCATOR.heap.lock().init(heap_start, KHEAPSIZE);
+    }
+    buddyinit(heap_end);
+
+    the_kmem().start = pool_end;
+    freerange(pool_end, PHYSTOP);
+}
+
+fn pa2index(pa: usize) -> usize {
+    (pa - KERNELBASE) / PGSIZE
+}
+
+fn freerange(pa_start: usize, pa_end: usize) {
+    let mut p = PGROUNDUP!(pa_start);
+    while p + PGSIZE <= pa_end {
+        the_kmem().refcnt[pa2index(p)] = 1;
+        kfree(p as *mut u8);
+        p += PGSIZE;
+    }
+}
+
+// Drop a reference to the page of physical memory pointed at by pa,
+// which normally should have been returned by a
+// call to kalloc().  (The exception is when
+// initializing the allocator; see kinit above.)
+// The page is freed when that was the last reference.
+pub fn kfree(pa: *mut u8) {
+    if pa as usize % PGSIZE != 0 || (pa as usize) < the_kmem().start || pa as usize >= PHYSTOP {
+        panic!("kfree");
+    }
+
+    kmem_lock.lock();
+    let refcnt = &mut the_kmem().refcnt[pa2index(pa as usize)];
+    if *refcnt == 0 {
+        panic!("kfree: free page");
+    }
+    *refcnt -= 1;
+    let last = *refcnt == 0;
+    kmem_lock.unlock();
+    if !last {
+        return;
+    }
+
+    // Fill with junk to catch dangling refs.
+    unsafe { pa.write_bytes(1, PGSIZE) };
+
+    let r = pa as *mut Run;
+
+    kmem_lock.lock();
+    let km = the_kmem();
+    unsafe { (*r).next = km.freelist };
+    km.freelist = r;
+    kmem_lock.unlock();
+}
+
+// Allocate one 4096-byte page of physical memory.
+// Returns a pointer that the kernel can use,
+// or None if the memory cannot be allocated.
+pub fn kalloc() -> Option<*mut u8> {
+    kmem_lock.lock();
+    let km = the_kmem();
+    let r = km.freelist;
+    if !r.is_null() {
+        km.freelist = unsafe { (*r).next };
+        km.refcnt[pa2index(r as usize)] = 1;
+    }
+    kmem_lock.unlock();
+
+    if r.is_null() {
+        return None;
+    }
+    unsafe { (r as *mut u8).write_bytes(5, PGSIZE) }; // fill with junk
+    Some(r as *mut u8)
+}
+
+// Add a reference to a page from kalloc(), which is now shared.
+pub fn kdup(pa: usize) {
+    if pa % PGSIZE != 0 || pa < the_kmem().start || pa >= PHYSTOP {
+        panic!("kdup");
+    }
+    kmem_lock.lock();
+    let refcnt = &mut the_kmem().refcnt[pa2index(pa)];
+    if *refcnt == 0 {
+        panic!("kdup: free page");
+    }
+    *refcnt += 1;
+    kmem_lock.unlock();
+}
+
+// Number of references to a page from kalloc().
+pub fn krefcnt(pa: usize) -> usize {
+    kmem_lock.lock();
+    let n = the_kmem().refcnt[pa2index(pa)];
+    kmem_lock.unlock();
+    n as usize
+}
+
+// Print the state of the buddy allocator and the usage of the
+// kernel's object caches, for ^P on the console.
+pub fn kmemdump() {
+    buddy_dump();
+    slab_dump(&[&TRAPFRAME_CACHE, &FILE_CACHE, &PIPE_CACHE, &VIRTQ_REQ_CACHE]);
+}
@@ -8,6 +8,25 @@
 .globl kernelvec
 .align 4
 kernelvec:
+        // a page fault here is a kernel bug, possibly the kernel
+        // stack overflowing into its guard page, where there's no
+        // room to save registers. take page faults (scause 12, 13
+        // and 15) on this hart's part of TRAPSTACK instead, for
+        // kerneltrap() to report; they don't return.
+        csrw sscratch, t0
+        csrr t0, scause
+        addi t0, t0, -12
+        bltz t0, 1f
+        addi t0, t0, -3
+        bgtz t0, 1f
+        // sp = TRAPSTACK + (hartid + 1) * TRAPSTACKSIZE
+        la t0, TRAPSTACK
+        addi sp, tp, 1
+        slli sp, sp, {TRAPSTACKSHIFT}
+        add sp, sp, t0
+1:
+        csrr t0, sscratch
+
         // make room to save registers.
         addi sp, sp, -256
 
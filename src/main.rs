@@ -2,28 +2,43 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 #![feature(const_maybe_uninit_zeroed)]
-#![allow(dead_code, non_upper_case_globals)]
+#![allow(non_upper_case_globals)]
 
+mod bio;
+mod buddy;
+mod console;
+mod elf;
+mod exec;
+mod file;
+mod fs;
+mod kalloc;
 mod mem_utils;
 mod memolayout;
 mod params;
+mod pipe;
 mod plic;
 mod proc;
 mod riscv;
+mod slab;
+mod sleep_lock;
 mod spin_lock;
 mod start;
 mod syscall;
+mod sysfile;
+mod sysproc;
 mod trap;
 mod uart;
 mod utils;
 mod virtio;
 mod vm;
+mod vma;
 
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::Ordering;
 use core::{arch::global_asm, panic::PanicInfo};
 use linked_list_allocator::LockedHeap;
 use plic::plicinithart;
 use riscv::intr_on;
-use virtio::virtio_blk::virtio_disk_rw;
 
 use crate::plic::plicinit;
 
@@ -31,28 +46,56 @@ extern crate alloc;
 
 global_asm!(include_str!("entry.asm"));
 global_asm!(include_str!("trampoline.asm"));
-global_asm!(include_str!("kernelvec.asm"));
+global_asm!(
+    include_str!("kernelvec.asm"),
+    TRAPSTACKSHIFT = const trap::TRAPSTACKSHIFT,
+);
 global_asm!(include_str!("switch.asm"));
 
 #[no_mangle]
 static STACK0: StackWrapper = StackWrapper([0; 65536]);
 
 #[repr(align(65536))]
+#[allow(dead_code)]
 struct StackWrapper([u8; 65536]);
 
+// Small allocations come from the kmalloc slab caches, the rest
+// from the heap that kinit() sets aside.
+struct KernelAllocator {
+    heap: LockedHeap,
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match slab::kmalloc(layout) {
+            Some(ptr) => ptr,
+            None => self.heap.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (bottom, top) = {
+            let heap = self.heap.lock();
+            (heap.bottom(), heap.top())
+        };
+        if (bottom..top).contains(&(ptr as usize)) {
+            self.heap.dealloc(ptr, layout);
+        } else {
+            slab::kmfree(ptr, layout);
+        }
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    heap: LockedHeap::empty(),
+};
 
 #[no_mangle]
 pub extern "C" fn main() -> ! {
-    let heap_start = crate::memolayout::get_kernel_end();
-    let heap_end = crate::memolayout::PHYSTOP;
-    let heap_size = heap_end - heap_start;
-    unsafe {
-        ALLOCATOR.lock().init(heap_start, heap_size);
-    }
+    kalloc::kinit(); // physical page allocator, and the heap
     virtio::init_virtio_blk_device(memolayout::VIRTIO0 as *const u8);
-    uart::console_init();
+    console::consoleinit();
     plicinit();
     plicinithart();
     vm::kvminit();
@@ -61,17 +104,19 @@ pub extern "C" fn main() -> ! {
     trap::trapinithart();
     proc::userinit();
     intr_on();
-    virtio_disk_rw([0x75; 1024], true);
     proc::scheduler();
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    // print!() goes through uartputc_sync(), which works
+    // with interrupts off and without the transmit buffer.
     println!("{}", _info);
+    uart::PANICKED.store(true, Ordering::Relaxed); // freeze uart output from other CPUs
     loop {}
 }
 
 #[alloc_error_handler]
-fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout);
 }
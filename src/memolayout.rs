@@ -61,24 +61,33 @@ pub fn clint_mtimecmp(hartid: u64) -> u64 {
     return (CLINT as u64) + 0x4000 + 8 * hartid;
 }
 
+// pages in each process's kernel stack. KSTACK!() leaves an
+// unmapped guard page below each one.
+// A power of two: stacks come from buddy_alloc(), which would
+// round anything else up and waste the difference.
+pub const KSTACKPAGES: usize = 16;
+
 #[macro_export]
 macro_rules! KSTACK {
     ($p: expr) => {
-        TRAMPOLINE - ($p + 1) * 16 * PGSIZE
+        TRAMPOLINE - ($p + 1) * ($crate::memolayout::KSTACKPAGES + 1) * PGSIZE
     };
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn plic_priority() -> usize {
     PLIC + 0x0
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn plic_pending() -> usize {
     PLIC + 0x1000
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn plic_menable(hart: usize) -> usize {
     PLIC + 0x2000 + hart * 0x100
 }
@@ -88,6 +97,7 @@ pub fn plic_senable(hart: usize) -> usize {
     PLIC + 0x2080 + hart * 0x100
 }
 
+#[allow(dead_code)]
 pub fn plic_mpriority(hart: usize) -> usize {
     PLIC + 0x200000 + hart * 0x2000
 }
@@ -96,6 +106,7 @@ pub fn plic_spriority(hart: usize) -> usize {
     PLIC + 0x201000 + hart * 0x2000
 }
 
+#[allow(dead_code)]
 pub fn plic_mclaim(hart: usize) -> usize {
     PLIC + 0x200004 + hart * 0x2000
 }
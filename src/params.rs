@@ -1,6 +1,7 @@
 pub const NPROC: usize = 64; // maximum number of processes
 pub const NCPU: usize = 1; // maximum number of CPUs
 pub const NOFILE: usize = 16; // open files per process
+pub const NVMA: usize = 16; // memory-mapped regions per process
 pub const NFILE: usize = 100; // open files per system
 pub const NINODE: usize = 50; // maximum number of active i-nodes
 pub const NDEV: usize = 10; // maximum major device number
@@ -9,5 +10,9 @@ pub const MAXARG: usize = 32; // max exec arguments
 pub const MAXOPBLOCKS: usize = 10; // max # of blocks any FS op writes
 pub const LOGSIZE: usize = MAXOPBLOCKS * 3; // max data blocks in on-disk log
 pub const NBUF: usize = MAXOPBLOCKS * 3; // size of disk block cache
+#[allow(dead_code)]
 pub const FSSIZE: usize = 1000; // size of file system in blocks
 pub const MAXPATH: usize = 128; // maximum file path name
+pub const USERSTACK: usize = 1; // user stack pages
+pub const BSIZE: usize = 1024; // block size
+pub const KHEAPSIZE: usize = 4 * 1024 * 1024; // bytes set aside for the kernel heap (alloc)
@@ -0,0 +1,194 @@
+use core::mem::{align_of, size_of};
+use core::ptr::addr_of;
+
+use crate::file::{filealloc, fileclose, File, FileType};
+use crate::proc::{myproc, proc, sleep, wakeup};
+use crate::slab::KmemCache;
+use crate::spin_lock::SpinLock;
+use crate::vm::{copyin, copyout};
+
+const PIPESIZE: usize = 512;
+
+pub static PIPE_CACHE: KmemCache = KmemCache::new(
+    "pipe",
+    size_of::<Pipe>(),
+    align_of::<Pipe>(),
+    Some(pipe_ctor),
+);
+
+pub struct Pipe {
+    lock: SpinLock,
+    data: [u8; PIPESIZE],
+    nread: u32,      // number of bytes read
+    nwrite: u32,     // number of bytes written
+    readopen: bool,  // read fd is still open
+    writeopen: bool, // write fd is still open
+}
+
+// sleep()/wakeup() channels for readers and writers.
+fn read_chan(pi: *const Pipe) -> usize {
+    unsafe { addr_of!((*pi).nread) as usize }
+}
+
+fn write_chan(pi: *const Pipe) -> usize {
+    unsafe { addr_of!((*pi).nwrite) as usize }
+}
+
+// Nothing to read yet, but the write end is still open.
+unsafe fn pipe_empty(pi: *const Pipe) -> bool {
+    (*pi).nread == (*pi).nwrite && (*pi).writeopen
+}
+
+// Set up the lock of each pipe in PIPE_CACHE once; pipeclose()
+// frees a pipe with the lock released, ready for the next user.
+fn pipe_ctor(obj: *mut u8) {
+    unsafe {
+        (obj as *mut Pipe).write(Pipe {
+            lock: SpinLock::new(),
+            data: [0; PIPESIZE],
+            nread: 0,
+            nwrite: 0,
+            readopen: false,
+            writeopen: false,
+        });
+    }
+}
+
+// Allocate a pipe and a pair of files for its two ends:
+// the first reads from it, the second writes to it.
+pub fn pipealloc() -> Option<(&'static mut File, &'static mut File)> {
+    let f0 = filealloc()?;
+    let f1 = match filealloc() {
+        Some(f1) => f1,
+        None => {
+            fileclose(f0);
+            return None;
+        }
+    };
+
+    let pi = match PIPE_CACHE.alloc() {
+        Some(obj) => obj as *mut Pipe,
+        None => {
+            fileclose(f0);
+            fileclose(f1);
+            return None;
+        }
+    };
+    unsafe {
+        (*pi).nread = 0;
+        (*pi).nwrite = 0;
+        (*pi).readopen = true;
+        (*pi).writeopen = true;
+    }
+
+    f0.type_ = FileType::Pipe;
+    f0.readable = true;
+    f0.writable = false;
+    f0.pipe = pi;
+    f1.type_ = FileType::Pipe;
+    f1.readable = false;
+    f1.writable = true;
+    f1.pipe = pi;
+    Some((f0, f1))
+}
+
+pub fn pipeclose(pi: &mut Pipe, writable: bool) {
+    pi.lock.lock();
+    if writable {
+        pi.writeopen = false;
+        wakeup(read_chan(pi));
+    } else {
+        pi.readopen = false;
+        wakeup(write_chan(pi));
+    }
+    if !pi.readopen && !pi.writeopen {
+        pi.lock.unlock();
+        PIPE_CACHE.free(pi as *mut Pipe as *mut u8);
+    } else {
+        pi.lock.unlock();
+    }
+}
+
+// pipewrite() and piperead() take pi as a raw pointer, not a
+// &mut Pipe: the other end changes the pipe while they sleep, so
+// they must re-read its fields every time around.
+pub fn pipewrite(pi: *mut Pipe, addr: u64, n: i32) -> i32 {
+    let pid = myproc().unwrap();
+    let pagetable = unsafe { proc[pid].pagetable };
+    let lock = unsafe { &(*pi).lock };
+    let mut i = 0;
+
+    lock.lock();
+    while i < n {
+        if unsafe { !(*pi).readopen || proc[pid].killed } {
+            lock.unlock();
+            return -1;
+        }
+        if unsafe { (*pi).nwrite == (*pi).nread.wrapping_add(PIPESIZE as u32) } {
+            // pipe is full
+            wakeup(read_chan(pi));
+            sleep(write_chan(pi), lock);
+        } else {
+            let mut ch = 0u8;
+            if copyin(
+                unsafe { &mut *pagetable },
+                &mut ch,
+                (addr + i as u64) as usize,
+                1,
+            )
+            .is_err()
+            {
+                break;
+            }
+            unsafe {
+                let nwrite = (*pi).nwrite;
+                (*pi).data[nwrite as usize % PIPESIZE] = ch;
+                (*pi).nwrite = nwrite.wrapping_add(1);
+            }
+            i += 1;
+        }
+    }
+    wakeup(read_chan(pi));
+    lock.unlock();
+
+    i
+}
+
+pub fn piperead(pi: *mut Pipe, addr: u64, n: i32) -> i32 {
+    let pid = myproc().unwrap();
+    let pagetable = unsafe { proc[pid].pagetable };
+    let lock = unsafe { &(*pi).lock };
+
+    lock.lock();
+    while unsafe { pipe_empty(pi) } {
+        if unsafe { proc[pid].killed } {
+            lock.unlock();
+            return -1;
+        }
+        sleep(read_chan(pi), lock);
+    }
+    let mut i = 0;
+    while i < n {
+        // copy data out
+        let nread = unsafe { (*pi).nread };
+        if nread == unsafe { (*pi).nwrite } {
+            break;
+        }
+        let ch = unsafe { (*pi).data[nread as usize % PIPESIZE] };
+        if copyout(
+            unsafe { &mut *pagetable },
+            (addr + i as u64) as usize,
+            &ch,
+            1,
+        )
+        .is_err()
+        {
+            break;
+        }
+        unsafe { (*pi).nread = nread.wrapping_add(1) };
+        i += 1;
+    }
+    wakeup(write_chan(pi)); // piperead-wakeup
+    lock.unlock();
+    i
+}
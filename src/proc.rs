@@ -1,25 +1,52 @@
-use core::mem::MaybeUninit;
+use core::mem::{align_of, size_of, MaybeUninit};
 use spin::Mutex;
 
-use crate::mem_utils::slice_cpy;
-use crate::memolayout::{get_trampoline, TRAMPOLINE, TRAPFRAME};
-use crate::params::{NCPU, NPROC};
-use crate::riscv::{r_tp, PGSIZE, PTE_R, PTE_W, PTE_X};
+use crate::file::{fileclose, filedup, File};
+use crate::fs::log::{begin_op, end_op};
+use crate::fs::{fsinit, idup, iput, namei, Inode};
+use crate::mem_utils::{memmove, slice_cpy};
+use crate::memolayout::{get_trampoline, KSTACKPAGES, TRAMPOLINE, TRAPFRAME};
+use crate::params::{NCPU, NOFILE, NPROC, NVMA, ROOTDEV};
+use crate::riscv::{intr_get, intr_on, r_tp, PGSIZE, PTE_R, PTE_W, PTE_X};
+use crate::slab::KmemCache;
+use crate::spin_lock::{pop_off, push_off, SpinLock};
 use crate::trap::usertrapret;
 use crate::utils::get_ref_addr;
-use crate::vm::{kalloc, mappages, uvmcreate, uvminit, PageTable};
+use crate::vm::{
+    copyin, copyout, mappages, uvmcopy, uvmcreate, uvmdealloc, uvmfree, uvminit, uvmunmap,
+    PageTable,
+};
+use crate::vma::{vmacopy, vmafloor, vmafree, vmaunmapall, Vma};
+use crate::PGROUNDDOWN;
 
 // Saved registers for kernel context switches.
 
+pub static TRAPFRAME_CACHE: KmemCache = KmemCache::new(
+    "trapframe",
+    size_of::<Trapframe>(),
+    align_of::<Trapframe>(),
+    None,
+);
+
 pub static mut next_pid: Mutex<i32> = Mutex::new(1);
 
 pub static proc_locks: [crate::spin_lock::SpinLock; NPROC] =
     unsafe { MaybeUninit::zeroed().assume_init() };
+
+// helps ensure that wakeups of wait()ing
+// parents are not lost. helps obey the
+// memory model when using p->parent.
+// must be acquired before any p->lock.
+pub static wait_lock: SpinLock = SpinLock::new();
+
+// the first user process; orphans are reparented to it.
+pub static mut initproc: *mut Proc = 0 as *mut Proc;
 pub static mut proc: [Proc; NPROC] = unsafe { MaybeUninit::zeroed().assume_init() }; // because this is convient
 pub static mut cpus: [Cpu; NCPU] = unsafe { MaybeUninit::zeroed().assume_init() };
 
 /// riscv64-linux-gnu-gcc -c initcode.S  -o initcode.o
-/// riscv64-linux-gnu-objcopy -S -O binary initcode.o initcode
+/// riscv64-linux-gnu-ld -N -e start -Ttext 0 -o initcode.out initcode.o
+/// riscv64-linux-gnu-objcopy -S -O binary initcode.out initcode
 /// od -t xC initcode
 /// copy content of initcode into here
 // with loop
@@ -37,11 +64,11 @@ pub static mut cpus: [Cpu; NCPU] = unsafe { MaybeUninit::zeroed().assume_init()
 //0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
 //0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
 //];
-pub static initcode: [u8; 60] = [
-    0x17, 0x05, 0x00, 0x00, 0x03, 0x35, 0x05, 0x00, 0x97, 0x05, 0x00, 0x00, 0x83, 0xb5, 0x05, 0x00,
-    0x93, 0x08, 0x20, 0x07, 0x09, 0xa0, 0x73, 0x00, 0x00, 0x00, 0xf5, 0xbf, 0x93, 0x08, 0xa0, 0x02,
-    0x73, 0x00, 0x00, 0x00, 0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x01,
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+pub static initcode: [u8; 52] = [
+    0x17, 0x05, 0x00, 0x00, 0x13, 0x05, 0x45, 0x02, 0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0x45, 0x02,
+    0x93, 0x08, 0x70, 0x00, 0x73, 0x00, 0x00, 0x00, 0x93, 0x08, 0x20, 0x00, 0x73, 0x00, 0x00, 0x00,
+    0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
 ];
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -74,8 +101,10 @@ pub struct Cpu {
 }
 
 // per-process data for the trap handling code in trampoline.S.
-// sits in a page by itself just under the trampoline page in the
-// user page table. not specially mapped in the kernel page table.
+// comes from TRAPFRAME_CACHE. the page it is in is mapped just under
+// the trampoline page in the user page table (without PTE_U, so the
+// user can't see it or its neighbours), which puts it at
+// trapframe_va(). not specially mapped in the kernel page table.
 // the sscratch register points here.
 // uservec in trampoline.S saves user registers in the trapframe,
 // then initializes registers from the trapframe's
@@ -87,6 +116,7 @@ pub struct Cpu {
 // return-to-user path via usertrapret() doesn't return through
 // the entire kernel call stack.
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub struct Trapframe {
     /*   0 */ pub kernel_satp: u64, // kernel page table
     /*   8 */ pub kernel_sp: u64, // top of process's kernel stack
@@ -142,23 +172,24 @@ pub struct Proc {
 
     // p->lock must be held when using these:
     pub state: ProcessState, // Process state
-    // void *chan;                  // If non-zero, sleeping on chan
-    pub killed: bool, // If non-zero, have been killed
-    pub xstate: i32,  // Exit status to be returned to parent's wait
-    pub pid: i32,     // Process ID
+    pub chan: usize,         // If non-zero, sleeping on chan
+    pub killed: bool,        // If non-zero, have been killed
+    pub xstate: i32,         // Exit status to be returned to parent's wait
+    pub pid: i32,            // Process ID
 
     // wait_lock must be held when using this:
     pub parent: *mut Proc, // Parent process
 
     // these are private to the process, so p->lock need not be held.
-    pub kstack: u64,               // Virtual address of kernel stack
-    pub sz: u64,                   // Size of process memory (bytes)
-    pub pagetable: *mut PageTable, // User page table
-    pub trapframe: *mut Trapframe, // data page for trampoline.S
-    pub context: Context,          // swtch() here to run process
-    // struct file *ofile[NOFILE];  // Open files
-    // struct inode *cwd;           // Current directory
-    pub name: [u8; 16], // Process name (debugging)
+    pub kstack: u64,                // Virtual address of kernel stack
+    pub sz: u64,                    // Size of process memory (bytes)
+    pub pagetable: *mut PageTable,  // User page table
+    pub trapframe: *mut Trapframe,  // data page for trampoline.S
+    pub context: Context,           // swtch() here to run process
+    pub ofile: [*mut File; NOFILE], // Open files
+    pub cwd: *mut Inode,            // Current directory
+    pub vma: [Vma; NVMA],           // Memory-mapped regions
+    pub name: [u8; 16],             // Process name (debugging)
 }
 
 pub fn procinit() {
@@ -169,24 +200,50 @@ pub fn procinit() {
         }
     }
 }
-//return proc index
+// Look in the process table for an UNUSED proc.
+// If found, initialize state required to run in the kernel,
+// and return its index with proc_locks[index] held.
+// If there are no free procs, return None.
 pub fn allocproc() -> Option<usize> {
     for i in 0..NPROC {
+        proc_locks[i].lock();
         unsafe {
             let p = &mut proc[i];
             match p.state {
                 ProcessState::UNUSED => {
                     p.pid = get_next_pid();
                     p.state = ProcessState::USED;
-                    p.trapframe = kalloc() as *mut Trapframe;
-                    p.pagetable = proc_pagetable(p);
+
+                    // Allocate a trapframe.
+                    p.trapframe = match TRAPFRAME_CACHE.alloc() {
+                        Some(pa) => pa as *mut Trapframe,
+                        None => {
+                            freeproc(i);
+                            proc_locks[i].unlock();
+                            return None;
+                        }
+                    };
+
+                    // An empty user page table.
+                    p.pagetable = match proc_pagetable(p) {
+                        Some(pagetable) => pagetable,
+                        None => {
+                            freeproc(i);
+                            proc_locks[i].unlock();
+                            return None;
+                        }
+                    };
+
+                    #[cfg(feature = "kstack-watermark")]
+                    kstack_paint(p);
+
                     p.context = MaybeUninit::zeroed().assume_init();
                     p.context.ra = forkret as u64;
-                    p.context.sp = p.kstack + PGSIZE as u64;
+                    p.context.sp = p.kstack + (KSTACKPAGES * PGSIZE) as u64;
                     return Some(i);
                 }
                 _ => {
-                    break;
+                    proc_locks[i].unlock();
                 }
             }
         }
@@ -194,36 +251,109 @@ pub fn allocproc() -> Option<usize> {
     None
 }
 
-pub fn proc_pagetable(p: &Proc) -> *mut PageTable {
-    let pgtable_ptr;
-    pgtable_ptr = uvmcreate();
+// The process whose kernel stack has its guard page at va, if any.
+pub fn kstack_guard(va: usize) -> Option<usize> {
+    (0..NPROC).find(|&i| {
+        let guard = crate::KSTACK!(i) - PGSIZE;
+        (guard..guard + PGSIZE).contains(&va)
+    })
+}
+
+// With the kstack-watermark feature, allocproc() fills each kernel
+// stack with STACK_MAGIC, so that kstack_used() can tell how deep
+// it has been from how much of the pattern was overwritten.
+#[cfg(feature = "kstack-watermark")]
+const STACK_MAGIC: u64 = 0x57ac_57ac_57ac_57ac;
+
+#[cfg(feature = "kstack-watermark")]
+fn kstack_words(p: &Proc) -> &'static mut [u64] {
+    let n = KSTACKPAGES * PGSIZE / 8;
+    unsafe { core::slice::from_raw_parts_mut(p.kstack as *mut u64, n) }
+}
+
+#[cfg(feature = "kstack-watermark")]
+fn kstack_paint(p: &Proc) {
+    kstack_words(p).fill(STACK_MAGIC);
+}
+
+// The most bytes of its kernel stack p has used.
+#[cfg(feature = "kstack-watermark")]
+pub fn kstack_used(p: &Proc) -> usize {
+    let words = kstack_words(p);
+    // the stack grows down, so what it never reached is at the start.
+    let untouched = words.iter().take_while(|&&w| w == STACK_MAGIC).count();
+    (words.len() - untouched) * 8
+}
+
+// Where p's trapframe is in its user page table.
+pub fn trapframe_va(p: &Proc) -> usize {
+    TRAPFRAME + p.trapframe as usize % PGSIZE
+}
+
+// Create a user page table for a given process, with no user memory,
+// but with trampoline and trapframe pages.
+pub fn proc_pagetable(p: &Proc) -> Option<*mut PageTable> {
+    // An empty page table.
+    let pgtable_ptr = uvmcreate()?;
+    let pgtbl = unsafe { &mut *pgtable_ptr };
 
     // map the trampoline code (for system call return)
     // at the highest user virtual address.
     // only the supervisor uses it, on the way
     // to/from user space, so not PTE_U.
+    if !mappages(pgtbl, TRAMPOLINE, get_trampoline(), PGSIZE, PTE_R | PTE_X) {
+        uvmfree(pgtbl, 0);
+        return None;
+    }
+
+    // map the trapframe's page just below the trampoline page, for
+    // trampoline.S.
+    if !mappages(
+        pgtbl,
+        TRAPFRAME,
+        PGROUNDDOWN!(p.trapframe as usize),
+        PGSIZE,
+        PTE_R | PTE_W,
+    ) {
+        uvmunmap(pgtbl, TRAMPOLINE, 1, false);
+        uvmfree(pgtbl, 0);
+        return None;
+    }
+
+    Some(pgtable_ptr)
+}
+
+// Free a user page table and free the physical memory it refers to.
+pub fn proc_freepagetable(pgtbl: *mut PageTable, sz: u64) {
     unsafe {
-        mappages(
-            &mut *pgtable_ptr,
-            TRAMPOLINE,
-            get_trampoline(),
-            PGSIZE,
-            PTE_R | PTE_X,
-        );
-        mappages(
-            &mut *pgtable_ptr,
-            TRAPFRAME,
-            p.trapframe as usize,
-            PGSIZE,
-            PTE_R | PTE_W,
-        );
+        uvmunmap(&mut *pgtbl, TRAMPOLINE, 1, false);
+        uvmunmap(&mut *pgtbl, TRAPFRAME, 1, false);
+        uvmfree(&mut *pgtbl, sz as usize);
     }
-    pgtable_ptr
 }
 
-#[allow(unused_variables)]
+// free a proc structure and the data hanging from it,
+// including user pages.
+// proc_locks[i] must be held.
 pub fn freeproc(i: usize) {
-    unimplemented!()
+    let p = unsafe { &mut proc[i] };
+    if !p.trapframe.is_null() {
+        TRAPFRAME_CACHE.free(p.trapframe as *mut u8);
+    }
+    p.trapframe = 0 as *mut Trapframe;
+    if !p.pagetable.is_null() {
+        vmafree(p);
+        proc_freepagetable(p.pagetable, p.sz);
+    }
+    p.pagetable = 0 as *mut PageTable;
+    p.sz = 0;
+    p.pid = 0;
+    p.parent = 0 as *mut Proc;
+    p.name[0] = 0;
+    p.chan = 0;
+    p.killed = false;
+    p.xstate = 0;
+    p.state = ProcessState::UNUSED;
 }
 
 fn get_next_pid() -> i32 {
@@ -236,18 +366,34 @@ fn get_next_pid() -> i32 {
     pid
 }
 
+static mut FIRST: bool = true;
+
+// A fork child's very first scheduling by scheduler()
+// will swtch to forkret.
 pub fn forkret() {
     //we need release clock on curreent proc
     let proc_index = myproc().expect("forkret should have proc_index");
     proc_locks[proc_index].unlock();
-    //file system operation not implement
+
+    unsafe {
+        if FIRST {
+            // File system initialization must be run in the context of a
+            // regular process (e.g., because it calls sleep), and thus cannot
+            // be run from main().
+            FIRST = false;
+            fsinit(ROOTDEV as u32);
+        }
+    }
 
     usertrapret();
 }
 
 pub fn myproc() -> Option<usize> {
+    push_off();
     let cpu_index = cpuid();
-    unsafe { cpus[cpu_index].proc_index }
+    let proc_index = unsafe { cpus[cpu_index].proc_index };
+    pop_off();
+    proc_index
 }
 
 pub fn userinit() {
@@ -260,6 +406,308 @@ pub fn userinit() {
         (*p.trapframe).sp = PGSIZE as u64;
         p.state = ProcessState::RUNNABLE;
         slice_cpy(&mut p.name, "initcode".as_bytes());
+        p.cwd = namei(b"/").expect("userinit: no root") as *mut Inode;
+        initproc = p as *mut Proc;
+    }
+    proc_locks[proc_index].unlock();
+}
+
+// Grow or shrink user memory by n bytes.
+// Growing only moves p.sz; usertrap() allocates the pages
+// when they are first used (see uvmlazy()).
+// Returns Err if the new size is out of range.
+pub fn growproc(n: i32) -> Result<(), ()> {
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    let sz = p.sz as usize;
+    if n >= 0 {
+        let newsz = sz.checked_add(n as usize).ok_or(())?;
+        // keep clear of mmap() regions and the trapframe.
+        if newsz > vmafloor(p) {
+            return Err(());
+        }
+        p.sz = newsz as u64;
+    } else {
+        let newsz = sz.checked_sub(n.unsigned_abs() as usize).ok_or(())?;
+        p.sz = uvmdealloc(unsafe { &mut *p.pagetable }, sz, newsz) as u64;
+    }
+    Ok(())
+}
+
+// Create a new process, copying the parent.
+// Sets up child kernel stack to return as if from fork() system call.
+pub fn fork() -> i32 {
+    let p_index = myproc().unwrap();
+
+    // Allocate process.
+    let np_index = match allocproc() {
+        Some(i) => i,
+        None => return -1,
+    };
+
+    let pid;
+    unsafe {
+        let p = &mut proc[p_index];
+        let np = &mut proc[np_index];
+
+        // Copy user memory from parent to child.
+        if uvmcopy(&mut *p.pagetable, &mut *np.pagetable, p.sz as usize).is_err() {
+            freeproc(np_index);
+            proc_locks[np_index].unlock();
+            return -1;
+        }
+        np.sz = p.sz;
+
+        // and the memory-mapped regions.
+        if vmacopy(p, np).is_err() {
+            freeproc(np_index);
+            proc_locks[np_index].unlock();
+            return -1;
+        }
+
+        // copy saved user registers.
+        *np.trapframe = *p.trapframe;
+
+        // Cause fork to return 0 in the child.
+        (*np.trapframe).a0 = 0;
+
+        // increment reference counts on open file descriptors.
+        for i in 0..NOFILE {
+            if !p.ofile[i].is_null() {
+                np.ofile[i] = filedup(&mut *p.ofile[i]) as *mut File;
+            }
+        }
+        np.cwd = idup(&mut *p.cwd) as *mut Inode;
+
+        np.name = p.name;
+        pid = np.pid;
+        proc_locks[np_index].unlock();
+
+        wait_lock.lock();
+        np.parent = p as *mut Proc;
+        wait_lock.unlock();
+
+        proc_locks[np_index].lock();
+        np.state = ProcessState::RUNNABLE;
+        proc_locks[np_index].unlock();
+    }
+    pid
+}
+
+// Pass p's abandoned children to init.
+// Caller must hold wait_lock.
+fn reparent(p: *mut Proc) {
+    for i in 0..NPROC {
+        unsafe {
+            if proc[i].parent == p {
+                proc[i].parent = initproc;
+                wakeup(initproc as usize);
+            }
+        }
+    }
+}
+
+// Exit the current process.  Does not return.
+// An exited process remains in the zombie state
+// until its parent calls wait().
+pub fn exit(status: i32) -> ! {
+    let p_index = myproc().unwrap();
+    let p = unsafe { &mut proc[p_index] };
+
+    if p as *mut Proc == unsafe { initproc } {
+        panic!("init exiting");
+    }
+
+    // Unmap memory-mapped regions, which may write to files.
+    vmaunmapall(&mut p.vma, unsafe { &mut *p.pagetable });
+
+    // Close all open files.
+    for fd in 0..NOFILE {
+        if !p.ofile[fd].is_null() {
+            fileclose(unsafe { &mut *p.ofile[fd] });
+            p.ofile[fd] = 0 as *mut File;
+        }
+    }
+
+    begin_op();
+    iput(unsafe { &mut *p.cwd });
+    end_op();
+    p.cwd = 0 as *mut Inode;
+
+    wait_lock.lock();
+
+    // Give any children to init.
+    reparent(p as *mut Proc);
+
+    // Parent might be sleeping in wait().
+    wakeup(p.parent as usize);
+
+    proc_locks[p_index].lock();
+
+    #[cfg(feature = "kstack-watermark")]
+    {
+        let used = kstack_used(p);
+        if used > KSTACKPAGES * PGSIZE * 3 / 4 {
+            crate::println!(
+                "pid {} ({}): used {} of {} bytes of kernel stack",
+                p.pid,
+                crate::utils::cstr(&p.name),
+                used,
+                KSTACKPAGES * PGSIZE
+            );
+        }
+    }
+
+    p.xstate = status;
+    p.state = ProcessState::ZOMBIE;
+
+    wait_lock.unlock();
+
+    // Jump into the scheduler, never to return.
+    sched();
+    panic!("zombie exit");
+}
+
+// Wait for a child process to exit and return its pid.
+// Return -1 if this process has no children.
+pub fn wait(addr: u64) -> i32 {
+    let p_index = myproc().unwrap();
+    let p = unsafe { &mut proc[p_index] as *mut Proc };
+
+    wait_lock.lock();
+    loop {
+        // Scan through table looking for exited children.
+        let mut havekids = false;
+        for i in 0..NPROC {
+            let np = unsafe { &mut proc[i] };
+            if np.parent != p {
+                continue;
+            }
+            // make sure the child isn't still in exit() or swtch().
+            proc_locks[i].lock();
+
+            havekids = true;
+            if matches!(np.state, ProcessState::ZOMBIE) {
+                // Found one.
+                let pid = np.pid;
+                if addr != 0 {
+                    let xstate = np.xstate;
+                    let res = copyout(
+                        unsafe { &mut *(*p).pagetable },
+                        addr as usize,
+                        &xstate as *const i32 as *const u8,
+                        core::mem::size_of::<i32>(),
+                    );
+                    if res.is_err() {
+                        proc_locks[i].unlock();
+                        wait_lock.unlock();
+                        return -1;
+                    }
+                }
+                freeproc(i);
+                proc_locks[i].unlock();
+                wait_lock.unlock();
+                return pid;
+            }
+            proc_locks[i].unlock();
+        }
+
+        // No point waiting if we don't have any children.
+        if !havekids || unsafe { (*p).killed } {
+            wait_lock.unlock();
+            return -1;
+        }
+
+        // Wait for a child to exit.
+        sleep(p as usize, &wait_lock);
+    }
+}
+
+// Switch to scheduler.  Must hold only proc_locks[i] of the
+// current process and have changed its state.
+// Saves and restores intena because intena is a property of this
+// kernel thread, not this CPU.
+pub fn sched() {
+    let p_index = myproc().unwrap();
+    let p = unsafe { &mut proc[p_index] };
+    let cpu = unsafe { &mut cpus[cpuid()] };
+    if !proc_locks[p_index].holding() {
+        panic!("sched p->lock");
+    }
+    if cpu.noff != 1 {
+        panic!("sched locks");
+    }
+    if matches!(p.state, ProcessState::RUNNING) {
+        panic!("sched running");
+    }
+    if intr_get() {
+        panic!("sched interruptible");
+    }
+    let intena = cpu.intena;
+    unsafe {
+        swtch(
+            &mut p.context as *mut Context,
+            &mut cpu.context as *mut Context,
+        );
+        cpus[cpuid()].intena = intena;
+    }
+}
+
+// Give up the CPU for one scheduling round.
+pub fn yield_() {
+    let p_index = myproc().unwrap();
+    proc_locks[p_index].lock();
+    unsafe {
+        proc[p_index].state = ProcessState::RUNNABLE;
+    }
+    sched();
+    proc_locks[p_index].unlock();
+}
+
+// Atomically release lock and sleep on chan.
+// Reacquires lock when awakened.
+pub fn sleep(chan: usize, lk: &SpinLock) {
+    let p_index = myproc().unwrap();
+    let p = unsafe { &mut proc[p_index] };
+
+    // Must acquire proc_locks[p_index] in order to
+    // change p.state and then call sched.
+    // Once we hold proc_locks[p_index], we can be
+    // guaranteed that we won't miss any wakeup
+    // (wakeup locks proc_locks[p_index]),
+    // so it's okay to release lk.
+    proc_locks[p_index].lock();
+    lk.unlock();
+
+    // Go to sleep.
+    p.chan = chan;
+    p.state = ProcessState::SLEEPING;
+
+    sched();
+
+    // Tidy up.
+    p.chan = 0;
+
+    // Reacquire original lock.
+    proc_locks[p_index].unlock();
+    lk.lock();
+}
+
+// Wake up all processes sleeping on chan.
+// Must be called without any proc_locks held.
+pub fn wakeup(chan: usize) {
+    let me = myproc();
+    for i in 0..NPROC {
+        if me == Some(i) {
+            continue;
+        }
+        proc_locks[i].lock();
+        unsafe {
+            let p = &mut proc[i];
+            if matches!(p.state, ProcessState::SLEEPING) && p.chan == chan {
+                p.state = ProcessState::RUNNABLE;
+            }
+        }
+        proc_locks[i].unlock();
     }
 }
 
@@ -269,11 +717,10 @@ pub fn cpuid() -> usize {
 }
 
 pub fn procid() -> Option<usize> {
-    //push_off
+    push_off();
     let cpuid = cpuid();
     let procid = unsafe { cpus[cpuid].proc_index };
-
-    //pop_off
+    pop_off();
     procid
 }
 
@@ -283,6 +730,9 @@ pub fn scheduler() -> ! {
         let cpu = &mut cpus[cpuid];
         cpu.proc_index = None;
         loop {
+            // Avoid deadlock by ensuring that devices can interrupt.
+            intr_on();
+
             for i in 0..NPROC {
                 proc_locks[i].lock();
                 let p = &mut proc[i];
@@ -307,3 +757,27 @@ pub fn scheduler() -> ! {
 extern "C" {
     fn swtch(curr: *mut Context, next: *mut Context);
 }
+
+// Copy to either a user address, or kernel address,
+// depending on user_dst.
+pub fn either_copyout(user_dst: bool, dst: u64, src: *const u8, len: usize) -> Result<(), ()> {
+    if user_dst {
+        let p = unsafe { &proc[myproc().unwrap()] };
+        copyout(unsafe { &mut *p.pagetable }, dst as usize, src, len)
+    } else {
+        unsafe { memmove(dst as *mut u8, src, len) };
+        Ok(())
+    }
+}
+
+// Copy from either a user address, or kernel address,
+// depending on user_src.
+pub fn either_copyin(dst: *mut u8, user_src: bool, src: u64, len: usize) -> Result<(), ()> {
+    if user_src {
+        let p = unsafe { &proc[myproc().unwrap()] };
+        copyin(unsafe { &mut *p.pagetable }, dst, src as usize, len)
+    } else {
+        unsafe { memmove(dst, src as *const u8, len) };
+        Ok(())
+    }
+}
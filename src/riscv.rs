@@ -1,8 +1,10 @@
 use core::arch::asm;
 
 pub const MSTATUS_MPP_MASK: u64 = 3 << 11;
+#[allow(dead_code)]
 pub const MSTATUS_MPP_M: u64 = 3 << 11;
 pub const MSTATUS_MPP_S: u64 = 1 << 11;
+#[allow(dead_code)]
 pub const MSTATUS_MPP_U: u64 = 0 << 11;
 pub const MSTATUS_MIE: u64 = 1 << 3;
 
@@ -53,8 +55,10 @@ pub fn w_mepc(x: u64){
 // Supervisor Status Register, sstatus
 pub const SSTATUS_SPP: u64 = 1<<8;
 pub const SSTATUS_SPIE: u64 = 1<<5;
+#[allow(dead_code)]
 pub const SSTATUS_UPIE: u64 = 1<<4;
 pub const SSTATUS_SIE: u64 = 1<<1;
+#[allow(dead_code)]
 pub const SSTATUS_UIE: u64 = 1<<0;
 
 
@@ -132,8 +136,10 @@ pub fn w_sie(x: u64){
 }
 
 // Machine-mode Interrupt Enable
+#[allow(dead_code)]
 pub const MIE_MEIE: u64 = 1<<11;//external
 pub const MIE_MTIE: u64 = 1<<7;//timer
+#[allow(dead_code)]
 pub const MIE_MSIE: u64 = 1<<3;//software
 
 #[inline]
@@ -185,6 +191,7 @@ pub fn w_sepc(x: u64){
 
 // Machine Exception Delegation
 #[inline]
+#[allow(dead_code)]
 pub fn r_medeleg() -> u64{
     let mut x;
     unsafe {
@@ -208,6 +215,7 @@ pub fn w_medeleg(x: u64){
 
 // Machine Interrupt Delegation
 #[inline]
+#[allow(dead_code)]
 pub fn r_mideleg() -> u64{
     let mut x;
     unsafe {
@@ -231,6 +239,7 @@ pub fn w_mideleg(x: u64){
 // Supervisor Trap-Vector Base Address
 // low two bits are mode.
 #[inline]
+#[allow(dead_code)]
 pub fn r_stvec() -> u64{
     let mut x;
     unsafe {
@@ -283,8 +292,23 @@ pub fn w_pmpaddr0(x: u64){
     }
 }
 
-// use riscv's sv39 page table scheme.
+// riscv's sv39 and sv48 page table schemes.
+#[cfg_attr(feature = "sv48", allow(dead_code))]
 pub const SATP_SV39: u64 = 8<<60;
+#[cfg_attr(not(feature = "sv48"), allow(dead_code))]
+pub const SATP_SV48: u64 = 9<<60;
+
+// the scheme in use: sv39, or sv48 with the sv48 feature.
+#[cfg(not(feature = "sv48"))]
+pub const SATP_MODE: u64 = SATP_SV39;
+#[cfg(feature = "sv48")]
+pub const SATP_MODE: u64 = SATP_SV48;
+
+// levels of page-table pages: three for sv39, four for sv48.
+#[cfg(not(feature = "sv48"))]
+pub const PGLEVELS: usize = 3;
+#[cfg(feature = "sv48")]
+pub const PGLEVELS: usize = 4;
 
 //#define MAKE_SATP(pagetable) (SATP_SV39 | (((uint64)pagetable) >> 12))
 //I don't what this does
@@ -313,6 +337,7 @@ pub fn w_satp(x: u64){
 
 // Supervisor Scratch register, for early trap handler in trampoline.S.
 #[inline]
+#[allow(dead_code)]
 pub fn w_sscratch(x: u64){
     unsafe {
         asm! {
@@ -360,6 +385,7 @@ pub fn r_stval() -> u64{
 
 // Machine-mode Counter-Enable
 #[inline]
+#[allow(dead_code)]
 pub fn w_mcounteren(x: u64){
     unsafe {
         asm! {
@@ -370,6 +396,7 @@ pub fn w_mcounteren(x: u64){
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn r_mcounteren() -> u64{
     let mut x;
     unsafe {
@@ -383,6 +410,7 @@ pub fn r_mcounteren() -> u64{
 
 // machine-mode cycle counter
 #[inline]
+#[allow(dead_code)]
 pub fn r_time() -> u64{
     let mut x;
     unsafe {
@@ -414,6 +442,7 @@ pub fn intr_get() -> bool{
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn r_sp() -> u64{
     let mut x;
     unsafe {
@@ -450,6 +479,7 @@ pub fn w_tp(x: u64){
 }
 
 #[inline]
+#[allow(dead_code)]
 pub fn r_ra() -> u64{
     let mut x;
     unsafe {
@@ -480,13 +510,25 @@ pub const PTE_R: u64 = 1 << 1;
 pub const PTE_W: u64 = 1 << 2;
 pub const PTE_X: u64 = 1 << 3;
 pub const PTE_U: u64 = 1 << 4; // 1 -> user can access
+pub const PTE_D: u64 = 1 << 7; // dirty, set by the hardware on a write
+pub const PTE_COW: u64 = 1 << 8; // RSW: copy-on-write page, shared read-only
 
-pub const MAXVA: u64 = 1 << (9 + 9 + 9 + 12 -1);
+// one beyond the highest possible virtual address.
+// MAXVA is actually one bit less than the max allowed by
+// sv39/sv48, to avoid having to sign-extend virtual addresses
+// that have the high bit set.
+pub const MAXVA: u64 = 1 << (9 * PGLEVELS + PGSHIFT - 1);
 
 
-// extract the three 9-bit page table indices from a virtual address.
+// extract the PGLEVELS 9-bit page table indices from a virtual address.
 pub const PXMASK: u64 = 0x1FF; // 9bits
 #[macro_export]
+macro_rules! PGROUNDUP {
+    ($exp: expr) => {
+        ($exp + PGSIZE - 1) & !(PGSIZE - 1)
+    };
+}
+#[macro_export]
 macro_rules! PGROUNDDOWN {
     ($exp: expr) => {
         $exp & !(PGSIZE - 1)
@@ -507,6 +549,12 @@ macro_rules! PTE2PA {
     };
 }
 #[macro_export]
+macro_rules! PTE_FLAGS {
+    ($pte: expr) => {
+        $pte & 0x3FF
+    };
+}
+#[macro_export]
 macro_rules! PA2PTE {
     ($pa: expr) => {
         ($pa >> 12) << 10
@@ -516,6 +564,6 @@ macro_rules! PA2PTE {
 #[macro_export]
 macro_rules! MAKE_SATP {
     ($pgtbl_addr:expr) => {
-        SATP_SV39 | ($pgtbl_addr as u64 >> 12)
+        SATP_MODE | ($pgtbl_addr as u64 >> 12)
     };
 }
\ No newline at end of file
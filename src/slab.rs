@@ -0,0 +1,326 @@
+// Slab allocator: caches of fixed-size kernel objects, carved out
+// of pages from kalloc().
+//
+// Each slab is one page. A Slab header sits at the start of the
+// page and the objects follow it; free objects are chained through
+// their first word, or through a word after the object if the cache
+// has a constructor (see below). A cache keeps slabs with free objects on its
+// partial list and the others on its full list, and gives a slab's
+// page back to kalloc() once none of its objects are in use.
+//
+// An optional constructor runs on every object when its slab is
+// created, so objects come out of the cache already initialized.
+// Users must hand them back in that state. The free list link must
+// not overwrite what the constructor did, so it gets a word of its
+// own at the end of each object's slot.
+//
+// kmalloc()/kmfree() use a set of power-of-two caches for small
+// allocations; the global allocator in main.rs goes through them.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+use crate::kalloc::{kalloc, kfree};
+use crate::println;
+use crate::riscv::PGSIZE;
+use crate::spin_lock::SpinLock;
+use crate::PGROUNDDOWN;
+
+struct FreeObj {
+    next: *mut FreeObj,
+}
+
+// sits at the start of each slab's page.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObj, // free objects in this slab
+    inuse: usize,       // objects handed out from this slab
+}
+
+struct CacheInner {
+    partial: *mut Slab, // slabs with free objects
+    full: *mut Slab,    // slabs without
+    nslabs: usize,
+    inuse: usize,
+    allocs: usize,
+    frees: usize,
+}
+
+pub struct KmemCache {
+    name: &'static str,
+    size: usize,                   // object slot size, a multiple of align
+    align: usize,                  // object alignment
+    link: usize,                   // offset of the free list link in a slot
+    ctor: Option<fn(*mut u8)>,     // run on each object of a new slab
+    lock: SpinLock,                // protects inner
+    inner: UnsafeCell<CacheInner>, // slabs and statistics
+}
+
+// inner is only touched with lock held.
+unsafe impl Sync for KmemCache {}
+
+#[derive(Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub size: usize,    // object size
+    pub perslab: usize, // objects per slab
+    pub slabs: usize,   // pages in use by the cache
+    pub inuse: usize,   // objects handed out
+    pub allocs: usize,  // total alloc() calls that succeeded
+    pub frees: usize,   // total free() calls
+}
+
+impl KmemCache {
+    pub const fn new(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        ctor: Option<fn(*mut u8)>,
+    ) -> Self {
+        // room for the free list link, and keep every object aligned.
+        let align = if align < size_of::<FreeObj>() {
+            size_of::<FreeObj>()
+        } else {
+            align
+        };
+        // the link goes after a constructed object, not over it.
+        let word = size_of::<FreeObj>();
+        let link = if ctor.is_some() {
+            size.div_ceil(word) * word
+        } else {
+            0
+        };
+        let size = if ctor.is_some() { link + word } else { size };
+        let size = (size + align - 1) / align * align;
+        let cache = KmemCache {
+            name,
+            size,
+            align,
+            link,
+            ctor,
+            lock: SpinLock::new(),
+            inner: UnsafeCell::new(CacheInner {
+                partial: null_mut(),
+                full: null_mut(),
+                nslabs: 0,
+                inuse: 0,
+                allocs: 0,
+                frees: 0,
+            }),
+        };
+        assert!(cache.perslab() > 0, "KmemCache: object too big");
+        cache
+    }
+
+    // offset of the first object in a slab.
+    const fn first(&self) -> usize {
+        (size_of::<Slab>() + self.align - 1) / self.align * self.align
+    }
+
+    const fn perslab(&self) -> usize {
+        if self.first() >= PGSIZE {
+            return 0;
+        }
+        (PGSIZE - self.first()) / self.size
+    }
+
+    // the free list link of obj, and back.
+    fn obj2link(&self, obj: *mut u8) -> *mut FreeObj {
+        unsafe { obj.add(self.link) as *mut FreeObj }
+    }
+
+    fn link2obj(&self, o: *mut FreeObj) -> *mut u8 {
+        unsafe { (o as *mut u8).sub(self.link) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn inner(&self) -> &mut CacheInner {
+        unsafe { &mut *self.inner.get() }
+    }
+
+    // Get a fresh page from kalloc() and cut it into objects.
+    fn grow(&self) -> Option<*mut Slab> {
+        let page = kalloc()?;
+        let slab = page as *mut Slab;
+        let mut free: *mut FreeObj = null_mut();
+        // chain the objects so the lowest address comes out first.
+        for i in (0..self.perslab()).rev() {
+            let obj = unsafe { page.add(self.first() + i * self.size) };
+            if let Some(ctor) = self.ctor {
+                ctor(obj);
+            }
+            let o = self.obj2link(obj);
+            unsafe { (*o).next = free };
+            free = o;
+        }
+        unsafe {
+            slab.write(Slab {
+                prev: null_mut(),
+                next: null_mut(),
+                free,
+                inuse: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    // Allocate an object.
+    // Returns None if the cache needs a new slab and there is no
+    // memory for it.
+    pub fn alloc(&self) -> Option<*mut u8> {
+        self.lock.lock();
+        let inner = self.inner();
+
+        if inner.partial.is_null() {
+            match self.grow() {
+                Some(s) => push(&mut inner.partial, s),
+                None => {
+                    self.lock.unlock();
+                    return None;
+                }
+            }
+            inner.nslabs += 1;
+        }
+
+        let s = inner.partial;
+        let slab = unsafe { &mut *s };
+        let o = slab.free;
+        slab.free = unsafe { (*o).next };
+        slab.inuse += 1;
+        if slab.free.is_null() {
+            unlink(&mut inner.partial, s);
+            push(&mut inner.full, s);
+        }
+        inner.inuse += 1;
+        inner.allocs += 1;
+        self.lock.unlock();
+
+        Some(self.link2obj(o))
+    }
+
+    // Give back an object returned by alloc() on this cache.
+    pub fn free(&self, obj: *mut u8) {
+        let s = PGROUNDDOWN!(obj as usize) as *mut Slab;
+        let off = obj as usize - s as usize;
+        if off < self.first() || (off - self.first()) % self.size != 0 {
+            panic!("kmem_cache_free {}: bad object", self.name);
+        }
+
+        self.lock.lock();
+        let inner = self.inner();
+        let slab = unsafe { &mut *s };
+        if slab.inuse == 0 {
+            panic!("kmem_cache_free {}: slab not in use", self.name);
+        }
+        if slab.free.is_null() {
+            // was full.
+            unlink(&mut inner.full, s);
+            push(&mut inner.partial, s);
+        }
+        let o = self.obj2link(obj);
+        unsafe { (*o).next = slab.free };
+        slab.free = o;
+        slab.inuse -= 1;
+        inner.inuse -= 1;
+        inner.frees += 1;
+
+        if slab.inuse == 0 {
+            // nothing in use: the page goes back to kalloc().
+            unlink(&mut inner.partial, s);
+            inner.nslabs -= 1;
+            kfree(s as *mut u8);
+        }
+        self.lock.unlock();
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.lock.lock();
+        let inner = self.inner();
+        let st = SlabStats {
+            name: self.name,
+            size: self.size,
+            perslab: self.perslab(),
+            slabs: inner.nslabs,
+            inuse: inner.inuse,
+            allocs: inner.allocs,
+            frees: inner.frees,
+        };
+        self.lock.unlock();
+        st
+    }
+}
+
+fn push(head: &mut *mut Slab, s: *mut Slab) {
+    unsafe {
+        (*s).prev = null_mut();
+        (*s).next = *head;
+        if !head.is_null() {
+            (**head).prev = s;
+        }
+    }
+    *head = s;
+}
+
+fn unlink(head: &mut *mut Slab, s: *mut Slab) {
+    unsafe {
+        if (*s).prev.is_null() {
+            *head = (*s).next;
+        } else {
+            (*(*s).prev).next = (*s).next;
+        }
+        if !(*s).next.is_null() {
+            (*(*s).next).prev = (*s).prev;
+        }
+    }
+}
+
+// caches for kmalloc(), one per power-of-two size.
+const NKMALLOC: usize = 7;
+static KMALLOC_CACHES: [KmemCache; NKMALLOC] = [
+    KmemCache::new("kmalloc-16", 16, 16, None),
+    KmemCache::new("kmalloc-32", 32, 32, None),
+    KmemCache::new("kmalloc-64", 64, 64, None),
+    KmemCache::new("kmalloc-128", 128, 128, None),
+    KmemCache::new("kmalloc-256", 256, 256, None),
+    KmemCache::new("kmalloc-512", 512, 512, None),
+    KmemCache::new("kmalloc-1024", 1024, 1024, None),
+];
+
+// The cache kmalloc() uses for layout, if any: the smallest
+// one whose (naturally aligned) objects fit it.
+fn kmalloc_cache(layout: Layout) -> Option<&'static KmemCache> {
+    let size = layout.size().max(layout.align());
+    KMALLOC_CACHES.iter().find(|c| c.size >= size)
+}
+
+// Allocate memory for layout from the kmalloc caches.
+// Returns None if layout is too big for them or memory ran out;
+// the caller falls back to some other allocator.
+pub fn kmalloc(layout: Layout) -> Option<*mut u8> {
+    kmalloc_cache(layout)?.alloc()
+}
+
+// Free memory from kmalloc(layout).
+pub fn kmfree(ptr: *mut u8, layout: Layout) {
+    match kmalloc_cache(layout) {
+        Some(c) => c.free(ptr),
+        None => panic!("kmfree: not from kmalloc"),
+    }
+}
+
+fn print_stats(st: &SlabStats) {
+    println!(
+        "{}: size {} slabs {} ({}/slab) inuse {} allocs {} frees {}",
+        st.name, st.size, st.slabs, st.perslab, st.inuse, st.allocs, st.frees
+    );
+}
+
+// Print usage of the kmalloc caches and of caches, for debugging.
+pub fn slab_dump(caches: &[&KmemCache]) {
+    for c in KMALLOC_CACHES.iter().chain(caches.iter().copied()) {
+        print_stats(&c.stats());
+    }
+}
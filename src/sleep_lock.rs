@@ -0,0 +1,49 @@
+use core::cell::Cell;
+
+use crate::proc::{myproc, proc, sleep, wakeup};
+use crate::spin_lock::SpinLock;
+
+// Long-term locks for processes
+pub struct SleepLock {
+    locked: Cell<bool>, // Is the lock held?
+    lk: SpinLock,       // spinlock protecting this sleep lock
+
+    // For debugging:
+    pid: Cell<i32>, // Process holding lock
+}
+
+impl SleepLock {
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            locked: Cell::new(false),
+            lk: SpinLock::new(),
+            pid: Cell::new(0),
+        }
+    }
+
+    pub fn lock(&self) {
+        self.lk.lock();
+        while self.locked.get() {
+            sleep(self as *const SleepLock as usize, &self.lk);
+        }
+        self.locked.set(true);
+        self.pid.set(unsafe { proc[myproc().unwrap()].pid });
+        self.lk.unlock();
+    }
+
+    pub fn unlock(&self) {
+        self.lk.lock();
+        self.locked.set(false);
+        self.pid.set(0);
+        wakeup(self as *const SleepLock as usize);
+        self.lk.unlock();
+    }
+
+    pub fn holding(&self) -> bool {
+        self.lk.lock();
+        let r = self.locked.get() && self.pid.get() == unsafe { proc[myproc().unwrap()].pid };
+        self.lk.unlock();
+        r
+    }
+}
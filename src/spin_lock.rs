@@ -1,20 +1,79 @@
 use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
+use crate::proc::{cpuid, cpus};
+use crate::riscv::{intr_get, intr_off, intr_on};
+
 pub struct SpinLock {
     locked: AtomicBool,
+    cpu: AtomicUsize, // The cpu holding the lock.
 }
 
 impl SpinLock {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             locked: AtomicBool::new(false),
+            cpu: AtomicUsize::new(0),
         }
     }
+    // Acquire the lock.
+    // Loops (spins) until the lock is acquired.
     pub fn lock(&self) {
+        push_off(); // disable interrupts to avoid deadlock.
+        if self.holding() {
+            panic!("acquire");
+        }
         while self.locked.swap(true, Ordering::Acquire) {}
+        // Record info about lock acquisition for holding().
+        self.cpu.store(cpuid(), Ordering::Relaxed);
     }
-    pub fn unlock(&self){
+    pub fn unlock(&self) {
+        if !self.holding() {
+            panic!("release");
+        }
         self.locked.store(false, Ordering::Release);
+        pop_off();
+    }
+    // Check whether this cpu is holding the lock.
+    // Interrupts must be off.
+    pub fn holding(&self) -> bool {
+        self.locked.load(Ordering::Relaxed) && self.cpu.load(Ordering::Relaxed) == cpuid()
+    }
+}
+
+// Whether this cpu holds any spin lock, so that sleeping
+// would deadlock.
+pub fn holding_locks() -> bool {
+    push_off();
+    let held = unsafe { cpus[cpuid()].noff } > 1;
+    pop_off();
+    held
+}
+
+// push_off/pop_off are like intr_off()/intr_on() except that they are matched:
+// it takes two pop_off()s to undo two push_off()s.  Also, if interrupts
+// are initially off, then push_off, pop_off leaves them off.
+pub fn push_off() {
+    let old = intr_get();
+    intr_off();
+    let cpu = unsafe { &mut cpus[cpuid()] };
+    if cpu.noff == 0 {
+        cpu.intena = old;
+    }
+    cpu.noff += 1;
+}
+
+pub fn pop_off() {
+    let cpu = unsafe { &mut cpus[cpuid()] };
+    if intr_get() {
+        panic!("pop_off - interruptible");
+    }
+    if cpu.noff < 1 {
+        panic!("pop_off");
+    }
+    cpu.noff -= 1;
+    if cpu.noff == 0 && cpu.intena {
+        intr_on();
     }
 }
@@ -31,13 +31,19 @@ extern "C" fn start() {
     // access to all of physical memory.
     w_pmpaddr0(0x3fffffffffffff);
     w_pmpcfg0(0xf);
-    //timerinit();
+    // ask for clock interrupts.
+    timerinit();
     let id = r_mhartid();
     w_tp(id);
     unsafe{asm!("mret");}
 }
 
 
+// arrange to receive timer interrupts.
+// they will arrive in machine mode
+// at timervec in kernelvec.asm,
+// which turns them into software interrupts for
+// devintr() in trap.rs.
 fn timerinit(){
     let id = r_mhartid();
     let interval = 1000000; // cycles; about 1/10th second in qemu.
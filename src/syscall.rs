@@ -1,16 +1,144 @@
-use crate::print;
-use crate::{proc::{procid, proc}};
-pub fn syscall(){
-    let proc_index = procid().unwrap();
-    unsafe{
-        let proc_guard = &proc[proc_index];
-        let trapfram = &mut (*proc_guard.trapframe);
-        let num = trapfram.a7;
-        if num == 114{
-            print!("a");
-            loop {
-                
-            }
+use crate::println;
+use crate::proc::{myproc, proc};
+use crate::sysfile::{
+    sys_close, sys_dup, sys_exec, sys_mknod, sys_mmap, sys_munmap, sys_open, sys_pipe, sys_read,
+    sys_write,
+};
+use crate::sysproc::{sys_exit, sys_fork, sys_getpid, sys_sbrk, sys_wait};
+use crate::utils::cstr;
+use crate::vm::{copyin, copyinstr};
+
+// System call numbers, same as xv6 so its user programs work unchanged.
+pub const SYS_FORK: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_WAIT: usize = 3;
+pub const SYS_PIPE: usize = 4;
+pub const SYS_READ: usize = 5;
+#[allow(dead_code)]
+pub const SYS_KILL: usize = 6;
+pub const SYS_EXEC: usize = 7;
+#[allow(dead_code)]
+pub const SYS_FSTAT: usize = 8;
+#[allow(dead_code)]
+pub const SYS_CHDIR: usize = 9;
+pub const SYS_DUP: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_SBRK: usize = 12;
+#[allow(dead_code)]
+pub const SYS_SLEEP: usize = 13;
+#[allow(dead_code)]
+pub const SYS_UPTIME: usize = 14;
+pub const SYS_OPEN: usize = 15;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_MKNOD: usize = 17;
+#[allow(dead_code)]
+pub const SYS_UNLINK: usize = 18;
+#[allow(dead_code)]
+pub const SYS_LINK: usize = 19;
+#[allow(dead_code)]
+pub const SYS_MKDIR: usize = 20;
+pub const SYS_CLOSE: usize = 21;
+pub const SYS_MMAP: usize = 22;
+pub const SYS_MUNMAP: usize = 23;
+
+pub const NSYSCALL: usize = 24;
+
+// returned (negated) in a0 for a system call number we don't know.
+pub const ENOSYS: i64 = 38;
+
+// An array mapping syscall numbers to the function that handles the
+// system call. A None entry means the call is not implemented.
+static SYSCALLS: [Option<fn() -> i64>; NSYSCALL] = {
+    let mut table: [Option<fn() -> i64>; NSYSCALL] = [None; NSYSCALL];
+    table[SYS_FORK] = Some(sys_fork);
+    table[SYS_EXIT] = Some(sys_exit);
+    table[SYS_WAIT] = Some(sys_wait);
+    table[SYS_PIPE] = Some(sys_pipe);
+    table[SYS_READ] = Some(sys_read);
+    table[SYS_EXEC] = Some(sys_exec);
+    table[SYS_DUP] = Some(sys_dup);
+    table[SYS_GETPID] = Some(sys_getpid);
+    table[SYS_SBRK] = Some(sys_sbrk);
+    table[SYS_OPEN] = Some(sys_open);
+    table[SYS_WRITE] = Some(sys_write);
+    table[SYS_MKNOD] = Some(sys_mknod);
+    table[SYS_CLOSE] = Some(sys_close);
+    table[SYS_MMAP] = Some(sys_mmap);
+    table[SYS_MUNMAP] = Some(sys_munmap);
+    table
+};
+
+// Fetch the u64 at addr from the current process.
+pub fn fetchaddr(addr: u64) -> Result<u64, ()> {
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    // both tests needed, in case of overflow
+    if addr >= p.sz || addr + 8 > p.sz {
+        return Err(());
+    }
+    let mut ip: u64 = 0;
+    copyin(
+        unsafe { &mut *p.pagetable },
+        &mut ip as *mut u64 as *mut u8,
+        addr as usize,
+        8,
+    )?;
+    Ok(ip)
+}
+
+// Fetch the nul-terminated string at addr from the current process.
+// Returns length of string, not including nul.
+pub fn fetchstr(addr: u64, buf: &mut [u8]) -> Result<usize, ()> {
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    copyinstr(unsafe { &mut *p.pagetable }, buf, addr as usize)
+}
+
+fn argraw(n: usize) -> u64 {
+    let p = unsafe { &proc[myproc().unwrap()] };
+    let trapframe = unsafe { &*p.trapframe };
+    match n {
+        0 => trapframe.a0,
+        1 => trapframe.a1,
+        2 => trapframe.a2,
+        3 => trapframe.a3,
+        4 => trapframe.a4,
+        5 => trapframe.a5,
+        _ => panic!("argraw"),
+    }
+}
+
+// Fetch the nth 32-bit system call argument.
+pub fn argint(n: usize) -> i32 {
+    argraw(n) as i32
+}
+
+// Retrieve an argument as a pointer.
+// Doesn't check for legality, since
+// copyin/copyout will do that.
+pub fn argaddr(n: usize) -> u64 {
+    argraw(n)
+}
+
+// Fetch the nth word-sized system call argument as a null-terminated string.
+// Copies into buf, at most buf.len() bytes.
+// Returns string length if OK (not including nul).
+pub fn argstr(n: usize, buf: &mut [u8]) -> Result<usize, ()> {
+    let addr = argaddr(n);
+    fetchstr(addr, buf)
+}
+
+pub fn syscall() {
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    let num = unsafe { (*p.trapframe).a7 } as usize;
+    let ret = match SYSCALLS.get(num) {
+        Some(Some(f)) => f(),
+        _ => {
+            println!("{} {}: unknown sys call {}", p.pid, cstr(&p.name), num);
+            -ENOSYS
         }
+    };
+    // Use the trapframe pointer after the call, the handler
+    // may have replaced it.
+    unsafe {
+        (*p.trapframe).a0 = ret as u64;
     }
 }
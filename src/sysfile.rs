@@ -0,0 +1,371 @@
+//
+// File-system system calls.
+// Mostly argument checking, since we don't trust
+// user code, and calls into file.rs and fs.
+//
+
+use core::mem::size_of_val;
+use core::ptr::null_mut;
+
+use crate::exec::exec;
+use crate::file::{
+    filealloc, fileclose, filedup, fileread, filewrite, File, FileType, O_CREATE, O_RDWR, O_TRUNC,
+    O_WRONLY,
+};
+use crate::fs::layout::{DIRSIZ, T_DEVICE, T_DIR, T_FILE};
+use crate::fs::log::{begin_op, end_op};
+use crate::fs::{
+    dirlink, dirlookup, ialloc, ilock, itrunc, iunlock, iunlockput, iupdate, namei, nameiparent,
+    Inode,
+};
+use crate::kalloc::{kalloc, kfree};
+use crate::params::{MAXARG, MAXPATH, NDEV, NOFILE};
+use crate::pipe::pipealloc;
+use crate::proc::{myproc, proc};
+use crate::riscv::PGSIZE;
+use crate::syscall::{argaddr, argint, argstr, fetchaddr, fetchstr};
+use crate::vm::copyout;
+use crate::vma::{mmap, munmap, MAP_ANONYMOUS};
+
+// Fetch the nth word-sized system call argument as a file descriptor
+// and return both the descriptor and the corresponding File.
+fn argfd(n: usize) -> Option<(usize, &'static mut File)> {
+    let fd = argint(n);
+    if fd < 0 || fd as usize >= NOFILE {
+        return None;
+    }
+    let p = unsafe { &proc[myproc().unwrap()] };
+    let f = p.ofile[fd as usize];
+    if f.is_null() {
+        return None;
+    }
+    Some((fd as usize, unsafe { &mut *f }))
+}
+
+// Allocate a file descriptor for the given file.
+// Takes over file reference from caller on success.
+fn fdalloc(f: &mut File) -> Option<usize> {
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    let fd = p.ofile.iter().position(|of| of.is_null())?;
+    p.ofile[fd] = f as *mut File;
+    Some(fd)
+}
+
+pub fn sys_dup() -> i64 {
+    let f = match argfd(0) {
+        Some((_, f)) => f,
+        None => return -1,
+    };
+    match fdalloc(f) {
+        Some(fd) => {
+            filedup(f);
+            fd as i64
+        }
+        None => -1,
+    }
+}
+
+pub fn sys_read() -> i64 {
+    let p = argaddr(1);
+    let n = argint(2);
+    match argfd(0) {
+        Some((_, f)) => fileread(f, p, n) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_write() -> i64 {
+    let p = argaddr(1);
+    let n = argint(2);
+    match argfd(0) {
+        Some((_, f)) => filewrite(f, p, n) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_close() -> i64 {
+    let (fd, f) = match argfd(0) {
+        Some(x) => x,
+        None => return -1,
+    };
+    unsafe { proc[myproc().unwrap()].ofile[fd] = null_mut() };
+    fileclose(f);
+    0
+}
+
+fn create(path: &[u8], type_: i16, major: i16, minor: i16) -> Option<&'static mut Inode> {
+    let mut name = [0u8; DIRSIZ];
+    let dp = nameiparent(path, &mut name)?;
+
+    ilock(dp);
+
+    if let Some(ip) = dirlookup(dp, &name, None) {
+        iunlockput(dp);
+        ilock(ip);
+        if type_ == T_FILE && (ip.type_ == T_FILE || ip.type_ == T_DEVICE) {
+            return Some(ip);
+        }
+        iunlockput(ip);
+        return None;
+    }
+
+    let ip = match ialloc(dp.dev, type_) {
+        Some(ip) => ip,
+        None => {
+            iunlockput(dp);
+            return None;
+        }
+    };
+
+    ilock(ip);
+    ip.major = major;
+    ip.minor = minor;
+    ip.nlink = 1;
+    iupdate(ip);
+
+    let linked = if type_ == T_DIR {
+        // Create . and .. entries.
+        // No ip.nlink += 1 for ".": avoid cyclic ref count.
+        dirlink(ip, b".", ip.inum).and_then(|_| dirlink(ip, b"..", dp.inum))
+    } else {
+        Ok(())
+    };
+
+    if linked.and_then(|_| dirlink(dp, &name, ip.inum)).is_err() {
+        // something went wrong. de-allocate ip.
+        ip.nlink = 0;
+        iupdate(ip);
+        iunlockput(ip);
+        iunlockput(dp);
+        return None;
+    }
+
+    if type_ == T_DIR {
+        // now that success is guaranteed:
+        dp.nlink += 1; // for ".."
+        iupdate(dp);
+    }
+
+    iunlockput(dp);
+
+    Some(ip)
+}
+
+pub fn sys_open() -> i64 {
+    let mut path = [0u8; MAXPATH];
+    let omode = argint(1);
+    let len = match argstr(0, &mut path) {
+        Ok(len) => len,
+        Err(_) => return -1,
+    };
+    let path = &path[..len];
+
+    begin_op();
+
+    let ip = if omode & O_CREATE != 0 {
+        match create(path, T_FILE, 0, 0) {
+            Some(ip) => ip,
+            None => {
+                end_op();
+                return -1;
+            }
+        }
+    } else {
+        let ip = match namei(path) {
+            Some(ip) => ip,
+            None => {
+                end_op();
+                return -1;
+            }
+        };
+        ilock(ip);
+        if ip.type_ == T_DIR && omode != 0 {
+            iunlockput(ip);
+            end_op();
+            return -1;
+        }
+        ip
+    };
+
+    if ip.type_ == T_DEVICE && (ip.major < 0 || ip.major as usize >= NDEV) {
+        iunlockput(ip);
+        end_op();
+        return -1;
+    }
+
+    let f = match filealloc() {
+        Some(f) => f,
+        None => {
+            iunlockput(ip);
+            end_op();
+            return -1;
+        }
+    };
+    let fd = match fdalloc(f) {
+        Some(fd) => fd,
+        None => {
+            fileclose(f);
+            iunlockput(ip);
+            end_op();
+            return -1;
+        }
+    };
+
+    if ip.type_ == T_DEVICE {
+        f.type_ = FileType::Device;
+        f.major = ip.major;
+    } else {
+        f.type_ = FileType::Inode;
+        f.off = 0;
+    }
+    f.ip = ip as *mut Inode;
+    f.readable = omode & O_WRONLY == 0;
+    f.writable = omode & O_WRONLY != 0 || omode & O_RDWR != 0;
+
+    if omode & O_TRUNC != 0 && ip.type_ == T_FILE {
+        itrunc(ip);
+    }
+
+    iunlock(ip);
+    end_op();
+
+    fd as i64
+}
+
+pub fn sys_exec() -> i64 {
+    let mut path = [0u8; MAXPATH];
+    let len = match argstr(0, &mut path) {
+        Ok(len) => len,
+        Err(_) => return -1,
+    };
+    let uargv = argaddr(1);
+
+    // each argument string is copied into a page of its own.
+    let mut argv: [*mut u8; MAXARG] = [null_mut(); MAXARG];
+    let mut args: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let mut argc = 0;
+    let ret = loop {
+        if argc >= MAXARG {
+            break -1;
+        }
+        let uarg = match fetchaddr(uargv + (8 * argc) as u64) {
+            Ok(uarg) => uarg,
+            Err(_) => break -1,
+        };
+        if uarg == 0 {
+            break exec(&path[..len], &args[..argc]);
+        }
+        argv[argc] = match kalloc() {
+            Some(pa) => pa,
+            None => break -1,
+        };
+        let buf = unsafe { core::slice::from_raw_parts_mut(argv[argc], PGSIZE) };
+        argc += 1;
+        match fetchstr(uarg, buf) {
+            Ok(n) => args[argc - 1] = &buf[..n],
+            Err(_) => break -1,
+        }
+    };
+
+    for arg in argv.iter().take(argc) {
+        kfree(*arg);
+    }
+    ret
+}
+
+pub fn sys_mknod() -> i64 {
+    let mut path = [0u8; MAXPATH];
+
+    begin_op();
+    let major = argint(1) as i16;
+    let minor = argint(2) as i16;
+    let len = match argstr(0, &mut path) {
+        Ok(len) => len,
+        Err(_) => {
+            end_op();
+            return -1;
+        }
+    };
+    match create(&path[..len], T_DEVICE, major, minor) {
+        Some(ip) => iunlockput(ip),
+        None => {
+            end_op();
+            return -1;
+        }
+    }
+    end_op();
+    0
+}
+
+pub fn sys_pipe() -> i64 {
+    let fdarray = argaddr(0) as usize; // user pointer to array of two integers
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    let (rf, wf) = match pipealloc() {
+        Some(files) => files,
+        None => return -1,
+    };
+    let fd0 = match fdalloc(rf) {
+        Some(fd) => fd,
+        None => {
+            fileclose(rf);
+            fileclose(wf);
+            return -1;
+        }
+    };
+    let fd1 = match fdalloc(wf) {
+        Some(fd) => fd,
+        None => {
+            p.ofile[fd0] = null_mut();
+            fileclose(rf);
+            fileclose(wf);
+            return -1;
+        }
+    };
+    let fds = [fd0 as i32, fd1 as i32];
+    if copyout(
+        unsafe { &mut *p.pagetable },
+        fdarray,
+        fds.as_ptr() as *const u8,
+        size_of_val(&fds),
+    )
+    .is_err()
+    {
+        p.ofile[fd0] = null_mut();
+        p.ofile[fd1] = null_mut();
+        fileclose(rf);
+        fileclose(wf);
+        return -1;
+    }
+    0
+}
+
+pub fn sys_mmap() -> i64 {
+    // the address (argument 0) is only a hint, and is ignored.
+    let len = argaddr(1) as usize;
+    let prot = argint(2);
+    let flags = argint(3);
+    let off = argaddr(5) as usize;
+    let f = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        match argfd(4) {
+            Some((_, f)) => Some(f),
+            None => return -1,
+        }
+    };
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    match mmap(p, len, prot, flags, f, off) {
+        Ok(addr) => addr as i64,
+        Err(()) => -1,
+    }
+}
+
+pub fn sys_munmap() -> i64 {
+    let addr = argaddr(0) as usize;
+    let len = argaddr(1) as usize;
+    let p = unsafe { &mut proc[myproc().unwrap()] };
+    match munmap(p, addr, len) {
+        Ok(()) => 0,
+        Err(()) => -1,
+    }
+}
@@ -0,0 +1,29 @@
+use crate::proc::{exit, fork, growproc, myproc, proc, wait};
+use crate::syscall::{argaddr, argint};
+
+pub fn sys_getpid() -> i64 {
+    unsafe { proc[myproc().unwrap()].pid as i64 }
+}
+
+pub fn sys_fork() -> i64 {
+    fork() as i64
+}
+
+pub fn sys_exit() -> i64 {
+    let n = argint(0);
+    exit(n);
+}
+
+pub fn sys_wait() -> i64 {
+    let p = argaddr(0);
+    wait(p) as i64
+}
+
+pub fn sys_sbrk() -> i64 {
+    let n = argint(0);
+    let addr = unsafe { proc[myproc().unwrap()].sz };
+    if growproc(n).is_err() {
+        return -1;
+    }
+    addr as i64
+}
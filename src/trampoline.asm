@@ -21,7 +21,7 @@ uservec:
         # user page table.
         #
         # sscratch points to where the process's p->trapframe is
-        # mapped into user space, at TRAPFRAME.
+        # mapped into user space, in the page at TRAPFRAME.
         #
         
 	# swap a0 and sscratch
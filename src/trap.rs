@@ -3,18 +3,25 @@ use core::panic;
 use spin::Mutex;
 
 use crate::memolayout::{
-    get_kernelvec, get_trampoline, get_userret, get_uservec, TRAMPOLINE, TRAPFRAME, UART_IRQ,
+    get_kernelvec, get_trampoline, get_userret, get_uservec, KSTACKPAGES, TRAMPOLINE, UART_IRQ,
     VIRTIO0_IRQ,
 };
+use crate::params::NCPU;
 use crate::plic::{plic_claim, plic_complete};
-use crate::proc::{proc, procid, Trapframe, cpuid};
+use crate::proc::{
+    cpuid, exit, kstack_guard, proc, procid, trapframe_va, wakeup, yield_, ProcessState, Trapframe,
+};
 use crate::riscv::{
     intr_get, intr_off, intr_on, r_satp, r_scause, r_sepc, r_sstatus, r_stval, r_tp, w_sepc,
-    w_sstatus, w_stvec, PGSIZE, SATP_SV39, SSTATUS_SPIE, SSTATUS_SPP, w_sip, r_sip,
+    w_sstatus, w_stvec, PGSIZE, PTE_R, PTE_W, PTE_X, SATP_MODE, SSTATUS_SPIE, SSTATUS_SPP, w_sip,
+    r_sip,
 };
 use crate::syscall::syscall;
+use crate::utils::cstr;
 use crate::uart::uart_intr;
 use crate::virtio::virtio_blk::virtio_disk_intr;
+use crate::vm::{cowfault, uvmlazy};
+use crate::vma::vmafault;
 use crate::{println, MAKE_SATP};
 
 
@@ -39,7 +46,7 @@ pub fn usertrapret() {
     unsafe {
         let trapframe = &mut (*p.trapframe);
         trapframe.kernel_satp = r_satp(); // kernel page table
-        trapframe.kernel_sp = p.kstack + (PGSIZE as u64) * 15 as u64; // process's kernel stack
+        trapframe.kernel_sp = p.kstack + (KSTACKPAGES * PGSIZE) as u64; // process's kernel stack
         trapframe.kernel_trap = usertrap as u64;
         trapframe.kernel_hartid = r_tp(); // hartid for cpuid()
     }
@@ -55,15 +62,25 @@ pub fn usertrapret() {
     let epc = unsafe { (*p.trapframe).epc };
     w_sepc(epc);
     let satp = MAKE_SATP!(unsafe { (&(*p.pagetable).ptes as *const [u64; 512]) as u64 });
+    let trapframe = trapframe_va(p);
     drop(p);
     let fn_ptr_addr = (TRAMPOLINE + (get_userret() - get_trampoline())) as *const ();
     let fn_ptr: extern "C" fn(u64, u64) -> () = unsafe { core::mem::transmute(fn_ptr_addr) };
-    fn_ptr(TRAPFRAME as u64, satp);
+    fn_ptr(trapframe as u64, satp);
+}
+
+// The access a page fault with this scause needed, if it is one.
+fn fault_perm(scause: u64) -> Option<u64> {
+    match scause {
+        12 => Some(PTE_X), // instruction page fault
+        13 => Some(PTE_R), // load page fault
+        15 => Some(PTE_W), // store/AMO page fault
+        _ => None,
+    }
 }
 
 pub fn usertrap() {
     let mut intr_type = DevintrState::NotRecognized;
-    let mut proc_killed = false;
     // when a exception occurs, before we disable exception, another excpetion occurs
     // does this program can handle this?
     if (r_sstatus() & SSTATUS_SPP) != 0 {
@@ -78,10 +95,8 @@ pub fn usertrap() {
     trapfram.epc = r_sepc();
     if r_scause() == 8 {
         //syscall
-        proc_killed = p.killed;
-        if proc_killed {
-            //process killed
-            loop {}
+        if p.killed {
+            exit(-1);
         }
         // sepc points to the ecall instruction,
         // but we want to return to the next instruction.
@@ -92,6 +107,20 @@ pub fn usertrap() {
         drop(p);
         intr_on();
         syscall();
+    } else if r_scause() == 15
+        && cowfault(unsafe { &mut *p.pagetable }, r_stval() as usize).is_ok()
+    {
+        // store page fault on a copy-on-write page,
+        // which now has a private copy.
+    } else if (r_scause() == 13 || r_scause() == 15)
+        && uvmlazy(unsafe { &mut *p.pagetable }, r_stval() as usize, p.sz as usize).is_ok()
+    {
+        // load or store page fault on a page from sbrk(),
+        // mapped now that it is first used.
+    } else if fault_perm(r_scause())
+        .is_some_and(|perm| vmafault(p, r_stval() as usize, perm, true).is_ok())
+    {
+        // page fault in a memory-mapped region, now filled in.
     } else {
         intr_type = devintr();
         match intr_type {
@@ -99,20 +128,34 @@ pub fn usertrap() {
                 println!("usertrap(): unexpected scause {} pid={}", r_scause(), p.pid);
                 println!("            sepc={} stval={}", r_sepc(), r_stval());
                 p.killed = true;
-                proc_killed = p.killed;
             }
             _ => {}
         }
     }
-    if proc_killed {
-        //not implement
+    if unsafe { proc[proc_index].killed } {
+        exit(-1);
     }
+    // give up the CPU if this is a timer interrupt.
     if matches!(intr_type, DevintrState::TimerIntr) {
-        // yield, but not implememnt
+        yield_();
     }
     usertrapret();
 }
 
+// Stacks for kernelvec to handle kernel page faults on, one per
+// hart: a fault from a kernel stack overflowing into its guard page
+// can't be handled on that stack.
+// kernelvec.asm gets TRAPSTACKSHIFT from main.rs's global_asm!().
+pub const TRAPSTACKSHIFT: usize = 14;
+const TRAPSTACKSIZE: usize = 1 << TRAPSTACKSHIFT;
+
+#[no_mangle]
+static mut TRAPSTACK: TrapStack = TrapStack([0; TRAPSTACKSIZE * NCPU]);
+
+#[repr(align(16))]
+#[allow(dead_code)]
+struct TrapStack([u8; TRAPSTACKSIZE * NCPU]);
+
 #[no_mangle]
 pub fn kerneltrap() {
     let intr_type;
@@ -126,16 +169,37 @@ pub fn kerneltrap() {
     if intr_get() {
         panic!("kerneltrap: interrupts ");
     }
+    // kernelvec has switched to TRAPSTACK for a page fault, in
+    // case it was the kernel stack overflowing.
+    if (12..=15).contains(&scause) {
+        if let Some(i) = kstack_guard(r_stval() as usize) {
+            let p = unsafe { &proc[i] };
+            panic!(
+                "kernel stack overflow: pid {} ({}) sepc={:#x} stval={:#x}",
+                p.pid,
+                cstr(&p.name),
+                sepc,
+                r_stval()
+            );
+        }
+    }
     intr_type = devintr();
     if matches!(intr_type, DevintrState::NotRecognized) {
         println!("scause {}", scause);
         println!("sepc={} stval={}", r_sepc(), r_stval());
         panic!("kerneltrap");
     }
+    // give up the CPU if this is a timer interrupt.
     if matches!(intr_type, DevintrState::TimerIntr) {
-        // need yield cpu here
+        if let Some(i) = procid() {
+            if matches!(unsafe { proc[i].state }, ProcessState::RUNNING) {
+                yield_();
+            }
+        }
     }
 
+    // the yield_() may have caused some traps to occur,
+    // so restore trap registers for use by kernelvec.asm's sret instruction.
     w_sepc(sepc);
     w_sstatus(sstatus);
 }
@@ -184,5 +248,6 @@ fn clockintr()
 {
   let mut ticks_guard = TICKS.lock();
   (*ticks_guard)+=1;
-  //wakeup(&ticks);
+  drop(ticks_guard);
+  wakeup(&TICKS as *const Mutex<usize> as usize);
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
 use core::fmt;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
+use crate::console::consoleintr;
 use crate::memolayout::UART;
+use crate::proc::{sleep, wakeup};
+use crate::spin_lock::{pop_off, push_off, SpinLock};
 // use lazy_static::lazy_static;
 // use uart_16550::MmioSerialPort;
 // use spin::Mutex;
@@ -52,38 +57,160 @@ struct UartMimo {
     lsr: u8,
 }
 
-pub fn console_init() {
-    uart_init();
-}
-
 fn get_uart_ref<'a>() -> &'a mut UartMimo {
     let mut uart_ptr = unsafe { SERIAL_PORT.lock() };
     unsafe { &mut **uart_ptr }
 }
 
-fn uart_init() {
+pub fn uart_init() {
     let uart_ref = get_uart_ref();
-    uart_ref.ier = 0; //disable interrupts
-    uart_ref.lcr = LCR_BAUD_LATCH;
-    uart_ref.fcr_isr = 0x03; //LSB for baud rate 38.4k
-    uart_ref.ier = 0x00; //MSB for baud rate 38.4k
-
-    uart_ref.lcr = LCR_EIGHT_BITS; //leave set-baud mode and set word length to 8 bit
-    uart_ref.fcr_isr = FCR_FIFO_ENABLE | FCR_FIFO_CLEAR; //reset and clear FIFOs
-    uart_ref.ier = IER_TX_ENABLE | IER_RX_ENABLE; //enable transmit and receive interrupts
+    uart_ref.set_ier(0); //disable interrupts
+    uart_ref.set_lcr(LCR_BAUD_LATCH);
+    uart_ref.set_fcr(0x03); //LSB for baud rate 38.4k
+    uart_ref.set_ier(0x00); //MSB for baud rate 38.4k
+
+    uart_ref.set_lcr(LCR_EIGHT_BITS); //leave set-baud mode and set word length to 8 bit
+    uart_ref.set_fcr(FCR_FIFO_ENABLE | FCR_FIFO_CLEAR); //reset and clear FIFOs
+    uart_ref.set_ier(IER_TX_ENABLE | IER_RX_ENABLE); //enable transmit and receive interrupts
 }
 
 impl UartMimo {
+    // the registers are device memory: every access must reach the uart.
+    fn lsr(&self) -> u8 {
+        unsafe { read_volatile(&self.lsr) }
+    }
+
+    fn get_rhr(&self) -> u8 {
+        unsafe { read_volatile(&self.rhr_thr) }
+    }
+
+    fn put_thr(&mut self, c: u8) {
+        unsafe { write_volatile(&mut self.rhr_thr, c) }
+    }
+
+    fn set_ier(&mut self, v: u8) {
+        unsafe { write_volatile(&mut self.ier, v) }
+    }
+
+    fn set_fcr(&mut self, v: u8) {
+        unsafe { write_volatile(&mut self.fcr_isr, v) }
+    }
+
+    fn set_lcr(&mut self, v: u8) {
+        unsafe { write_volatile(&mut self.lcr, v) }
+    }
+
     fn _write_char(&mut self, c: u8) {
-        while self.lsr & LSR_TX_IDLE == 0 {}
-        self.rhr_thr = c;
+        uartputc_sync(c);
+    }
+}
+
+// the transmit output buffer.
+const UART_TX_BUF_SIZE: usize = 32;
+
+struct UartTx {
+    buf: [u8; UART_TX_BUF_SIZE],
+    w: u64, // write next to buf[w % UART_TX_BUF_SIZE]
+    r: u64, // read next from buf[r % UART_TX_BUF_SIZE]
+}
+
+static mut uart_tx: UartTx = UartTx {
+    buf: [0; UART_TX_BUF_SIZE],
+    w: 0,
+    r: 0,
+};
+static uart_tx_lock: SpinLock = SpinLock::new();
+
+// set by panic; freezes uart output from everyone else.
+pub static PANICKED: AtomicBool = AtomicBool::new(false);
+
+fn the_tx() -> &'static mut UartTx {
+    unsafe { &mut *addr_of_mut!(uart_tx) }
+}
+
+// uartputc() sleeps on this while the output buffer is full.
+fn tx_chan() -> usize {
+    unsafe { addr_of!(uart_tx.r) as usize }
+}
+
+// uartstart() moves uart_tx.r while uartputc() sleeps,
+// so this must go to memory every time.
+fn tx_full() -> bool {
+    unsafe { *addr_of!(uart_tx.w) == *addr_of!(uart_tx.r) + UART_TX_BUF_SIZE as u64 }
+}
+
+// add a character to the output buffer and tell the
+// uart to start sending if it isn't already.
+// blocks if the output buffer is full.
+// because it may block, it can't be called
+// from interrupts; it's only suitable for use
+// by write().
+pub fn uartputc(c: u8) {
+    uart_tx_lock.lock();
+
+    if PANICKED.load(Ordering::Relaxed) {
+        loop {}
+    }
+    while tx_full() {
+        // buffer is full.
+        // wait for uartstart() to open up space in the buffer.
+        sleep(tx_chan(), &uart_tx_lock);
     }
+    let tx = the_tx();
+    tx.buf[tx.w as usize % UART_TX_BUF_SIZE] = c;
+    tx.w += 1;
+    uartstart();
+    uart_tx_lock.unlock();
 }
 
+// alternate version of uartputc() that doesn't
+// use interrupts, for use by kernel print!() and
+// to echo characters. it spins waiting for the uart's
+// output register to be empty.
 pub fn uartputc_sync(c: u8) {
+    push_off();
+
+    if PANICKED.load(Ordering::Relaxed) {
+        loop {}
+    }
+
+    let uart_ref = get_uart_ref();
+    // wait for Transmit Holding Empty to be set in LSR.
+    while uart_ref.lsr() & LSR_TX_IDLE == 0 {}
+    uart_ref.put_thr(c);
+
+    pop_off();
+}
+
+// if the UART is idle, and a character is waiting
+// in the transmit buffer, send it.
+// caller must hold uart_tx_lock.
+// called from both the top- and bottom-half.
+fn uartstart() {
+    let tx = the_tx();
     let uart_ref = get_uart_ref();
-    while uart_ref.lsr & LSR_TX_IDLE == 0 {}
-    uart_ref.rhr_thr = c;
+    loop {
+        if tx.w == tx.r {
+            // transmit buffer is empty.
+            unsafe { read_volatile(&uart_ref.fcr_isr) };
+            return;
+        }
+
+        if uart_ref.lsr() & LSR_TX_IDLE == 0 {
+            // the UART transmit holding register is full,
+            // so we cannot give it another byte.
+            // it will interrupt when it's ready for a new byte.
+            return;
+        }
+
+        let c = tx.buf[tx.r as usize % UART_TX_BUF_SIZE];
+        tx.r += 1;
+
+        // maybe uartputc() is waiting for space in the buffer.
+        wakeup(tx_chan());
+
+        uart_ref.put_thr(c);
+    }
 }
 
 impl fmt::Write for UartMimo {
@@ -95,26 +222,31 @@ impl fmt::Write for UartMimo {
     }
 }
 
+// handle a uart interrupt, raised because input has
+// arrived, or the uart is ready for more output, or
+// both. called from devintr().
 pub fn uart_intr() {
+    // read and process incoming characters.
     loop {
         let c_opt = uart_getc();
         match c_opt {
             None => break,
-            Some(c) => console_intr(c),
+            Some(c) => consoleintr(c),
         }
     }
+
+    // send buffered characters.
+    uart_tx_lock.lock();
+    uartstart();
+    uart_tx_lock.unlock();
 }
 
 pub fn uart_getc() -> Option<u8> {
     let uart_ref = get_uart_ref();
-    if uart_ref.lsr & 0x01 != 0 {
+    if uart_ref.lsr() & LSR_RX_READY != 0 {
         // input data is ready
-        Some(uart_ref.rhr_thr)
+        Some(uart_ref.get_rhr())
     } else {
         None
     }
 }
-
-fn console_intr(c: u8) {
-    uartputc_sync(c);
-}
@@ -1,4 +1,5 @@
 use crate::{println, print};
+#[allow(dead_code)]
 pub fn print_addr(addr: u64, size: usize){
     for i in 0..size{
         if i % 16 == 0{
@@ -10,4 +11,9 @@ pub fn print_addr(addr: u64, size: usize){
 
 pub fn get_ref_addr<T>(v: &T) -> u64{
     v as *const T as u64
-}
\ No newline at end of file
+}
+// view a nul-terminated byte buffer (like Proc::name) as a str.
+pub fn cstr(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("?")
+}
@@ -1,9 +1,11 @@
+use core::ptr::addr_of_mut;
+
+use crate::buddy::buddy_alloc;
 use crate::mem_utils::memset;
 // use crate::println;
 use crate::riscv::PGSIZE;
 use crate::virtio::virtio_blk::{VirtqAvail, VirtqDesc, VirtqUsed, QUEUE_NUM};
-use crate::vm::kalloc;
-use virtio_blk::DISK;
+use virtio_blk::{DISK, DISK_LOCK};
 
 pub mod virtio_blk;
 
@@ -14,7 +16,9 @@ const STATUS_ACKNOWLEDGE: u32 = 1;
 const STATUS_DRIVER: u32 = 2;
 const STATUS_DRIVER_OK: u32 = 4;
 const STATUS_FEATURES_OK: u32 = 8;
+#[allow(dead_code)]
 const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+#[allow(dead_code)]
 const STATUS_FAILED: u32 = 128;
 
 const VIRTIO_F_INDIRECT_DESC: u32 = 1 << 28;
@@ -128,17 +132,23 @@ pub fn init_virtio_blk_device(dev_addr: *const u8) {
         panic!("virtio disk max queue too short");
     }
 
-    let disk_ref = &mut DISK.lock();
-
-    disk_ref.desc = kalloc() as *mut VirtqDesc;
-    disk_ref.avail = kalloc() as *mut VirtqAvail;
-    disk_ref.used = kalloc() as *mut VirtqUsed;
+    DISK_LOCK.lock();
+    let disk_ref = unsafe { &mut *addr_of_mut!(DISK) };
 
+    // the device reads the rings by physical address, and is
+    // told where each one is, so they needn't be contiguous.
+    // one page each.
+    let desc = buddy_alloc(1).expect("virtio disk buddy_alloc");
+    let avail = buddy_alloc(1).expect("virtio disk buddy_alloc");
+    let used = buddy_alloc(1).expect("virtio disk buddy_alloc");
     unsafe {
-        memset(disk_ref.desc as *mut u8, 0, PGSIZE);
-        memset(disk_ref.avail as *mut u8, 0, PGSIZE);
-        memset(disk_ref.used as *mut u8, 0, PGSIZE);
+        memset(desc, 0, PGSIZE);
+        memset(avail, 0, PGSIZE);
+        memset(used, 0, PGSIZE);
     }
+    disk_ref.desc = desc as *mut VirtqDesc;
+    disk_ref.avail = avail as *mut VirtqAvail;
+    disk_ref.used = used as *mut VirtqUsed;
 
     // set queue size
     dev_reg_ref.queue_num = QUEUE_NUM as u32;
@@ -156,9 +166,11 @@ pub fn init_virtio_blk_device(dev_addr: *const u8) {
 
     // ALL NUM descriptors start out unused
     for i in 0..QUEUE_NUM{
-        disk_ref.free[i] = false;
+        disk_ref.free[i] = true;
     }
 
+    DISK_LOCK.unlock();
+
     status |= STATUS_DRIVER_OK;
     dev_reg_ref.status = status;
     // plic.rs and trap.rs arrange for interrupts from VIRTIO0_IRQ.
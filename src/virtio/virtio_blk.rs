@@ -1,66 +1,92 @@
-use core::mem::size_of;
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering::Relaxed;
+use core::mem::{align_of, size_of};
+use core::ptr::addr_of_mut;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::fence;
+use core::sync::atomic::Ordering::SeqCst;
 
 use super::MMIODeviceLagacyRegisterLayout;
 use crate::memolayout::{self, VIRTIO0};
 use crate::println;
+use crate::proc::{sleep, wakeup};
 use crate::riscv::PGSIZE;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::slab::KmemCache;
+use crate::sleep_lock::SleepLock;
+use crate::spin_lock::SpinLock;
 
 use super::{VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
 
-lazy_static! {
-    pub static ref DISK: Mutex<Disk> = Mutex::new(Disk {
-        desc: 0 as *mut VirtqDesc,
-        avail: 0 as *mut VirtqAvail,
-        used: 0 as *mut VirtqUsed,
-        free: [true; QUEUE_NUM],
-        used_idx: 0,
-        info: [DiskInfo {
-            b: 0 as *mut DiskBuffer,
-            status: 0
-        }; QUEUE_NUM],
-        ops: [VirtqBlkReq {
-            type_filed: 0,
-            reserved: 0,
-            sector: 0,
-        }; QUEUE_NUM],
-    });
-}
-pub static mut is_finish_rw: AtomicBool = AtomicBool::new(true);
+pub static mut DISK: Disk = Disk {
+    desc: 0 as *mut VirtqDesc,
+    avail: 0 as *mut VirtqAvail,
+    used: 0 as *mut VirtqUsed,
+    free: [true; QUEUE_NUM],
+    used_idx: 0,
+    info: [DiskInfo {
+        b: 0 as *mut DiskBuffer,
+        status: 0,
+    }; QUEUE_NUM],
+};
+// protects DISK; sleep() releases it while a request is in flight.
+pub static DISK_LOCK: SpinLock = SpinLock::new();
+
+// the type/reserved/sector header of each request. the device reads
+// it by physical address, which is the same as the kernel's.
+pub static VIRTQ_REQ_CACHE: KmemCache = KmemCache::new(
+    "virtio_blk_req",
+    size_of::<VirtqBlkReq>(),
+    align_of::<VirtqBlkReq>(),
+    None,
+);
 
-pub const BSIZE: usize = 1024;
+pub use crate::params::BSIZE;
 
 pub const DEVICE_ID: u32 = 0x2;
 pub const VENDOR_ID: u32 = 0x554d4551;
 
 pub const QUEUE_NUM: usize = 8;
 
+#[allow(dead_code)]
 pub const DISK_PAGES_LEN: usize = 2 * PGSIZE;
 
+// VirtqDesc flags
+pub const VRING_DESC_F_NEXT: u16 = 1; // chained with another descriptor
+pub const VRING_DESC_F_WRITE: u16 = 2; // device writes (vs read)
+
 pub const VIRTIO_BLK_T_IN: u32 = 0; //read the disk
 pub const VIRTIO_BLK_T_OUT: u32 = 1; //write the disk
 
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_BARRIER: u32 = 1 << 0;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_GEOMETRY: u32 = 1 << 4;
 pub const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
 pub const VIRTIO_BLK_F_SCSI: u32 = 1 << 7;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
 pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
 pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_LIFETIME: u32 = 1 << 15;
+#[allow(dead_code)]
 pub const VIRTIO_BLK_F_SECURE_ERASE: u32 = 1 << 16;
+#[allow(dead_code)]
 pub const VIRTIO_F_NOTIFY_ON_EMPTY: u32 = 1 << 24;
 pub const VIRTIO_F_ANY_LAYOUT: u32 = 1 << 27;
+#[allow(dead_code)]
 pub const VIRTIO_UNUSED: u32 = 1 << 30;
+#[allow(dead_code)]
 pub fn list_feature(feature_bits: u32) {
     if feature_bits & VIRTIO_BLK_F_BARRIER != 0 {
         //        println!("VIRTIO_BLK_F_BARRIER");
@@ -132,7 +158,6 @@ pub struct Disk {
     pub free: [bool; QUEUE_NUM],
     pub used_idx: u16,
     pub info: [DiskInfo; QUEUE_NUM],
-    pub ops: [VirtqBlkReq; QUEUE_NUM],
 }
 
 unsafe impl Send for Disk {}
@@ -182,75 +207,193 @@ pub struct DiskInfo {
 }
 
 pub struct DiskBuffer {
-    valid: bool,
-    disk: bool, // does disk "own" buf?
-    // some filed omit
-    data: [u8; BSIZE],
+    pub valid: bool, // has data been read from disk?
+    pub disk: bool,  // does disk "own" buf?
+    pub dev: u32,
+    pub blockno: u32,
+    pub lock: SleepLock,
+    pub refcnt: u32,
+    pub last_use: u64, // when bio last released it, for LRU recycling
+    pub data: [u8; BSIZE],
+}
+
+// find a free descriptor, mark it non-free, return its index.
+fn alloc_desc(disk_ref: &mut Disk) -> Option<usize> {
+    let i = disk_ref.free.iter().position(|&free| free)?;
+    disk_ref.free[i] = false;
+    Some(i)
+}
+
+// mark a descriptor as free.
+fn free_desc(disk_ref: &mut Disk, i: usize) {
+    if i >= QUEUE_NUM {
+        panic!("free_desc 1");
+    }
+    if disk_ref.free[i] {
+        panic!("free_desc 2");
+    }
+    let desc = unsafe { &mut *disk_ref.desc.add(i) };
+    desc.addr = 0;
+    desc.len = 0;
+    desc.flags = 0;
+    desc.next = 0;
+    disk_ref.free[i] = true;
+    wakeup(&disk_ref.free[0] as *const bool as usize);
+}
+
+// free a chain of descriptors.
+fn free_chain(disk_ref: &mut Disk, i: usize) {
+    let mut i = i;
+    loop {
+        let desc = unsafe { &*disk_ref.desc.add(i) };
+        let flag = desc.flags;
+        let nxt = desc.next as usize;
+        free_desc(disk_ref, i);
+        if flag & VRING_DESC_F_NEXT == 0 {
+            break;
+        }
+        i = nxt;
+    }
+}
+
+// allocate three descriptors (they need not be contiguous).
+// disk transfers always use three descriptors.
+fn alloc3_desc(disk_ref: &mut Disk, idx: &mut [usize; 3]) -> bool {
+    for i in 0..3 {
+        match alloc_desc(disk_ref) {
+            Some(d) => idx[i] = d,
+            None => {
+                for j in 0..i {
+                    free_desc(disk_ref, idx[j]);
+                }
+                return false;
+            }
+        }
+    }
+    true
 }
 
 pub fn virtio_disk_intr() {
-    // let _disk = DISK.lock();
+    DISK_LOCK.lock();
+    let disk_ref = unsafe { &mut *addr_of_mut!(DISK) };
     // the device won't raise another interrupt until we tell it
     // we've seen this interrupt, which the following line does.
     // this may race with the device writing new entries to
     // the "used" ring, in which case we may process the new
     // completion entries in this interrupt, and have nothing to do
     // in the next interrupt, which is harmless.
-    let _dev_reg_ref = unsafe { &mut *(VIRTIO0 as u64 as *mut MMIODeviceLagacyRegisterLayout) };
-
+    let dev_reg_ref = unsafe { &mut *(VIRTIO0 as u64 as *mut MMIODeviceLagacyRegisterLayout) };
     unsafe {
-        is_finish_rw.store(true, Relaxed);
+        let status = read_volatile(&dev_reg_ref.interrupt_status);
+        write_volatile(&mut dev_reg_ref.interrupt_ack, status & 0x3);
+    }
+
+    fence(SeqCst);
+
+    // the device increments disk.used->idx when it
+    // adds an entry to the used ring.
+    let used_ref = unsafe { &*disk_ref.used };
+    while disk_ref.used_idx != unsafe { read_volatile(&used_ref.idx) } {
+        fence(SeqCst);
+        let elem = &used_ref.ring[disk_ref.used_idx as usize % QUEUE_NUM];
+        let id = unsafe { read_volatile(&elem.id) } as usize;
+
+        if disk_ref.info[id].status != 0 {
+            panic!("virtio_disk_intr status");
+        }
+
+        let b = disk_ref.info[id].b;
+        unsafe { (*b).disk = false }; // disk is done with buf
+        wakeup(b as usize);
+
+        disk_ref.used_idx = disk_ref.used_idx.wrapping_add(1);
     }
+
+    DISK_LOCK.unlock();
 }
 
-pub fn virtio_disk_rw(data: [u8; BSIZE], write: bool) {
-    let sector = 0;
-    let mut disk_ref = DISK.lock();
+// Read or write b.data from or to block b.blockno of the disk,
+// sleeping until the device has finished. When reading, the device
+// writes straight into b.data.
+pub fn virtio_disk_rw(b: &mut DiskBuffer, write: bool) {
+    let sector = b.blockno as u64 * (BSIZE / 512) as u64;
+    let req = VIRTQ_REQ_CACHE
+        .alloc()
+        .expect("virtio_disk_rw: no memory for request") as *mut VirtqBlkReq;
+    DISK_LOCK.lock();
+    let disk_ref = unsafe { &mut *addr_of_mut!(DISK) };
+
+    // the spec's Section 5.2 says that legacy block operations use
+    // three descriptors: one for type/reserved/sector, one for the
+    // data, one for a 1-byte status result.
+
+    // allocate the three descriptors.
+    let mut idx = [0usize; 3];
+    while !alloc3_desc(disk_ref, &mut idx) {
+        sleep(&disk_ref.free[0] as *const bool as usize, &DISK_LOCK);
+    }
 
     // format the three descriptors.
     // qemu's virtio-blk.c reads them.
-    let buf0 = &mut disk_ref.ops[0];
-    let buf0_addr = buf0 as *const VirtqBlkReq as u64;
+    let buf0 = unsafe { &mut *req };
+    let buf0_addr = req as u64;
     if write {
         buf0.type_filed = VIRTIO_BLK_T_OUT; // write the disk
     } else {
         buf0.type_filed = VIRTIO_BLK_T_IN; // read the disk
     }
-
-    // the spec's Section 5.2 says that legacy block operations use
-    // three descriptors: one for type/reserved/sector, one for the
-    // data, one for a 1-byte status result.
-
     buf0.reserved = 0;
     buf0.sector = sector;
+
     let desc_array = unsafe { &mut *(disk_ref.desc as *mut [VirtqDesc; QUEUE_NUM]) };
-    desc_array[0].addr = buf0_addr;
-    desc_array[0].len = size_of::<VirtqBlkReq>() as u32;
-    desc_array[0].flags = 1;
-    desc_array[0].next = 1;
+    desc_array[idx[0]].addr = buf0_addr;
+    desc_array[idx[0]].len = size_of::<VirtqBlkReq>() as u32;
+    desc_array[idx[0]].flags = VRING_DESC_F_NEXT;
+    desc_array[idx[0]].next = idx[1] as u16;
 
-    desc_array[1].addr = &data as *const [u8; BSIZE] as u64;
-    desc_array[1].len = BSIZE as u32;
+    desc_array[idx[1]].addr = b.data.as_mut_ptr() as u64;
+    desc_array[idx[1]].len = BSIZE as u32;
     if write {
-        desc_array[1].flags = 0;
+        desc_array[idx[1]].flags = 0; // device reads b.data
+    } else {
+        desc_array[idx[1]].flags = VRING_DESC_F_WRITE; // device writes b.data
     }
-    desc_array[1].flags |= 1;
-    desc_array[1].next = 2;
-
-    disk_ref.info[0].status = 0xff;
-    desc_array[2].addr = &disk_ref.info[0].status as *const u8 as u64;
-    desc_array[2].len = 1;
-    desc_array[2].flags = 2;
-    desc_array[2].next = 0;
-
-    let mut avail_ref = unsafe { &mut *disk_ref.avail };
-    avail_ref.ring[avail_ref.idx as usize % QUEUE_NUM] = 0;
-    avail_ref.idx += 1;
+    desc_array[idx[1]].flags |= VRING_DESC_F_NEXT;
+    desc_array[idx[1]].next = idx[2] as u16;
+
+    disk_ref.info[idx[0]].status = 0xff; // device writes 0 on success
+    desc_array[idx[2]].addr = &disk_ref.info[idx[0]].status as *const u8 as u64;
+    desc_array[idx[2]].len = 1;
+    desc_array[idx[2]].flags = VRING_DESC_F_WRITE; // device writes the status
+    desc_array[idx[2]].next = 0;
+
+    // record struct buf for virtio_disk_intr().
+    b.disk = true;
+    disk_ref.info[idx[0]].b = b as *mut DiskBuffer;
+
+    // tell the device the first index in our chain of descriptors.
+    let avail_ref = unsafe { &mut *disk_ref.avail };
+    avail_ref.ring[avail_ref.idx as usize % QUEUE_NUM] = idx[0] as u16;
+
+    fence(SeqCst);
+
+    // tell the device another avail ring entry is available.
+    avail_ref.idx = avail_ref.idx.wrapping_add(1);
+
+    fence(SeqCst);
+
     let dev_reg_ref =
         unsafe { &mut *(memolayout::VIRTIO0 as u64 as *mut MMIODeviceLagacyRegisterLayout) };
-    unsafe {
-        is_finish_rw.store(false, Relaxed);
+    unsafe { write_volatile(&mut dev_reg_ref.queue_notify, 0) }; // value is queue number
+
+    // Wait for virtio_disk_intr() to say request has finished.
+    while unsafe { read_volatile(&b.disk) } {
+        sleep(b as *mut DiskBuffer as usize, &DISK_LOCK);
     }
-    dev_reg_ref.queue_notify = 0; // start device r/w operation
-    unsafe { while !is_finish_rw.load(Relaxed) {} }
+
+    disk_ref.info[idx[0]].b = 0 as *mut DiskBuffer;
+    free_chain(disk_ref, idx[0]);
+
+    DISK_LOCK.unlock();
+    VIRTQ_REQ_CACHE.free(req as *mut u8);
 }
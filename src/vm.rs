@@ -1,11 +1,20 @@
-use core::alloc::Layout;
+use core::cmp::min;
 use core::panic;
+use core::ptr;
 
-use crate::mem_utils::memmove;
-use crate::memolayout::{get_etext, get_trampoline, KERNELBASE, PHYSTOP, PLIC, TRAMPOLINE, UART, VIRTIO0};
+use crate::mem_utils::{memmove, memset};
+use crate::memolayout::{
+    get_etext, get_trampoline, KERNELBASE, KSTACKPAGES, PHYSTOP, PLIC, TRAMPOLINE, TRAPFRAME, UART,
+    VIRTIO0,
+};
 use crate::params::NPROC;
-use crate::{riscv::*, ALLOCATOR};
-use crate::{MAKE_SATP, PA2PTE, PGROUNDDOWN, PTE2PA, PX};
+use crate::proc::{myproc, proc};
+use crate::buddy::buddy_alloc;
+use crate::kalloc::{kalloc, kdup, kfree, krefcnt};
+use crate::riscv::*;
+use crate::spin_lock::holding_locks;
+use crate::vma::vmafault;
+use crate::{MAKE_SATP, PA2PTE, PGROUNDDOWN, PGROUNDUP, PTE2PA, PTE_FLAGS, PX};
 #[repr(C)]
 pub struct PageTable {
     pub ptes: [u64; 512],
@@ -19,7 +28,8 @@ pub static mut KERN_PG_ADDR: *mut PageTable = 0 as *mut PageTable;
 //we need make our kernel to use direct map
 pub fn kvminit() {
     unsafe {
-        KERN_PG_ADDR = kalloc() as *mut PageTable;
+        KERN_PG_ADDR = kalloc().expect("kvminit") as *mut PageTable;
+        (*KERN_PG_ADDR).ptes.fill(0);
         kvmmake(&mut *KERN_PG_ADDR);
     }
 }
@@ -66,16 +76,22 @@ fn kvmmap(pgtbl: &mut PageTable, va: usize, pa: usize, sz: usize, perm: u64) {
 
 fn proc_mapstack(pgtbl: &mut PageTable) {
     for i in 0..NPROC {
-        let pa = kalloc_n_pages(15);
+        let pa = buddy_alloc(KSTACKPAGES).expect("proc_mapstack: buddy_alloc");
         let va = crate::KSTACK!(i);
-        kvmmap(pgtbl, va , pa as usize, PGSIZE * 15, PTE_R | PTE_W);
+        kvmmap(pgtbl, va, pa as usize, PGSIZE * KSTACKPAGES, PTE_R | PTE_W);
     }
 }
 
+// highest level with leaf PTEs in kernel mappings: a leaf at
+// level 1 maps a 2 MiB superpage, at level 2 a 1 GiB one.
+const MAXLEAFLEVEL: usize = 2;
+
 // Create PTEs for virtual addresses starting at va that refer to
 // physical addresses starting at pa. va and size might not
 // be page-aligned. Returns true on success, false if walk() couldn't
 // allocate a needed page-table page.
+// Kernel mappings use superpages where va, pa and the size allow;
+// user memory is always mapped, and unmapped, a page at a time.
 pub fn mappages(pgtbl: &mut PageTable, va: usize, pa: usize, sz: usize, perm: u64) -> bool {
     if sz == 0 {
         panic!("mappages: size of zero");
@@ -85,80 +101,112 @@ pub fn mappages(pgtbl: &mut PageTable, va: usize, pa: usize, sz: usize, perm: u6
     let last = PGROUNDDOWN!(va + sz - 1);
     let mut pa = pa;
     loop {
-        let pte = walk(pgtbl, a, true).expect("mappages: walk return error!");
+        let level = if perm & PTE_U != 0 {
+            0
+        } else {
+            (0..=MAXLEAFLEVEL)
+                .rev()
+                .find(|&l| {
+                    let size = PGSIZE << (9 * l);
+                    a % size == 0 && pa % size == 0 && last - a + PGSIZE >= size
+                })
+                .unwrap()
+        };
+        let size = PGSIZE << (9 * level);
+        let pte = match walklevel(pgtbl, a, level, true) {
+            Ok(pte) => pte,
+            Err(()) => return false,
+        };
         if (*pte & PTE_V) == 1 {
             panic!("mappages: remap");
         }
         *pte = PA2PTE!(pa as u64) | perm | PTE_V;
-        if a == last {
+        if a + size > last {
             break;
         }
-        a += PGSIZE;
-        pa += PGSIZE;
+        a += size;
+        pa += size;
     }
     true
 }
 
-fn walk(pgtbl: &mut PageTable, va: usize, alloc: bool) -> Result<&mut u64, ()> {
+// Return the address of the PTE in page table pagetable
+// that corresponds to virtual address va.  If alloc is true,
+// create any required page-table pages.
+// Returns Err for va at or above MAXVA, which may come from a
+// user pointer, or if there is no page-table page for va.
+// If va is in a superpage, that is the superpage's leaf PTE in
+// a higher level.
+pub fn walk(pgtbl: &mut PageTable, va: usize, alloc: bool) -> Result<&mut u64, ()> {
+    walklevel(pgtbl, va, 0, alloc)
+}
+
+// Like walk(), but for the PTE at level (0 is the last one), which
+// maps PGSIZE << (9 * level) bytes if it is a leaf.
+fn walklevel(pgtbl: &mut PageTable, va: usize, level: usize, alloc: bool) -> Result<&mut u64, ()> {
     let mut pgtb_addr: *mut [u64; 512] = &mut pgtbl.ptes as *mut [u64; 512]; // turn a around with rust's safety requirement
     if va >= MAXVA as usize {
-        panic!("walk: virtual address excess MAXVA");
+        return Err(());
     }
 
-    for level in (1..=2).rev() {
-        let pte: &mut u64 = unsafe { &mut (*pgtb_addr)[PX!(level, va)] };
+    for l in (level + 1..PGLEVELS).rev() {
+        let pte: &mut u64 = unsafe { &mut (*pgtb_addr)[PX!(l, va)] };
         if (*pte & PTE_V) == 1 {
+            if *pte & (PTE_R | PTE_W | PTE_X) != 0 {
+                // a superpage.
+                return Ok(pte);
+            }
             pgtb_addr = PTE2PA!(*pte) as *mut [u64; 512];
         } else {
             if !alloc {
                 return Err(());
             }
-            pgtb_addr = kalloc() as *mut [u64; 512];
+            pgtb_addr = kalloc().ok_or(())? as *mut [u64; 512];
             unsafe {
                 (*(pgtb_addr as *mut [u64; 512])).as_mut_slice().fill(0);
             }
             *pte = PA2PTE!(pgtb_addr as u64) | PTE_V;
         }
     }
-    Ok(unsafe { &mut (*pgtb_addr)[PX!(0, va)] })
+    Ok(unsafe { &mut (*pgtb_addr)[PX!(level, va)] })
 }
 
-pub fn kalloc() -> *mut u8 {
-    unsafe {
-        ALLOCATOR
-            .lock()
-            .allocate_first_fit(Layout::from_size_align_unchecked(PGSIZE, PGSIZE))
-            .expect("kalloc error")
-            .as_ptr()
+// Look up a virtual address, return the physical address,
+// or None if not mapped.
+// Can only be used to look up user pages.
+pub fn walkaddr(pgtbl: &mut PageTable, va: usize) -> Option<usize> {
+    if va >= MAXVA as usize {
+        return None;
     }
-}
-
-pub fn kalloc_n_pages(n: usize) -> *mut u8{
-    unsafe{
-        ALLOCATOR
-            .lock()
-            .allocate_first_fit(Layout::from_size_align_unchecked(PGSIZE * n, PGSIZE))
-            .expect("kalloc_n_pages error")
-            .as_ptr()
+    let pte = walk(pgtbl, va, false).ok()?;
+    if *pte & PTE_V == 0 || *pte & PTE_U == 0 {
+        return None;
     }
+    Some(PTE2PA!(*pte) as usize)
 }
 
 pub fn kvminithart() {
-    w_satp(MAKE_SATP!(unsafe { KERN_PG_ADDR }));
+    let satp = MAKE_SATP!(unsafe { KERN_PG_ADDR });
+    w_satp(satp);
+    // the mode field reads back as it was if the hart
+    // doesn't support SATP_MODE.
+    if r_satp() != satp {
+        panic!("kvminithart: paging mode not supported");
+    }
     sfence_vma();
 }
 
 // create an empty user page table.
-
-pub fn uvmcreate() -> *mut PageTable {
-    let pagetable = kalloc() as *mut PageTable;
+// returns None if out of memory.
+pub fn uvmcreate() -> Option<*mut PageTable> {
+    let pagetable = kalloc()? as *mut PageTable;
     unsafe { (*pagetable).ptes.as_mut_slice().fill(0) };
-    return pagetable;
+    Some(pagetable)
 }
 
 pub fn uvminit(pgtbl: &mut PageTable, initcode: &[u8]) {
     let sz = initcode.len();
-    let mem = kalloc();
+    let mem = kalloc().expect("uvminit: kalloc");
     mappages(
         pgtbl,
         0,
@@ -166,5 +214,328 @@ pub fn uvminit(pgtbl: &mut PageTable, initcode: &[u8]) {
         PGSIZE,
         PTE_W | PTE_R | PTE_X | PTE_U,
     );
-    unsafe { memmove(mem, initcode.as_ptr(), sz) };
+    unsafe {
+        memset(mem, 0, PGSIZE);
+        memmove(mem, initcode.as_ptr(), sz);
+    }
+}
+
+// Remove npages of mappings starting from va. va must be
+// page-aligned. The mappings must exist.
+// Optionally free the physical memory.
+pub fn uvmunmap(pgtbl: &mut PageTable, va: usize, npages: usize, do_free: bool) {
+    if va % PGSIZE != 0 {
+        panic!("uvmunmap: not aligned");
+    }
+    for a in (va..va + npages * PGSIZE).step_by(PGSIZE) {
+        // pages from sbrk() that were never touched aren't mapped.
+        let pte = match walk(pgtbl, a, false) {
+            Ok(pte) if *pte & PTE_V != 0 => pte,
+            _ => continue,
+        };
+        if PTE_FLAGS!(*pte) == PTE_V {
+            panic!("uvmunmap: not a leaf");
+        }
+        if do_free {
+            kfree(PTE2PA!(*pte) as *mut u8);
+        }
+        *pte = 0;
+    }
+}
+
+// Allocate PTEs and physical memory to grow process from oldsz to
+// newsz, which need not be page aligned.  Returns new size or Err on error.
+pub fn uvmalloc(
+    pgtbl: &mut PageTable,
+    oldsz: usize,
+    newsz: usize,
+    xperm: u64,
+) -> Result<usize, ()> {
+    if newsz < oldsz {
+        return Ok(oldsz);
+    }
+    // user memory ends at the trapframe.
+    if newsz > TRAPFRAME {
+        return Err(());
+    }
+
+    let oldsz = PGROUNDUP!(oldsz);
+    for a in (oldsz..newsz).step_by(PGSIZE) {
+        let mem = match kalloc() {
+            Some(mem) => mem,
+            None => {
+                uvmdealloc(pgtbl, a, oldsz);
+                return Err(());
+            }
+        };
+        unsafe { memset(mem, 0, PGSIZE) };
+        if !mappages(pgtbl, a, mem as usize, PGSIZE, PTE_R | PTE_U | xperm) {
+            kfree(mem);
+            uvmdealloc(pgtbl, a, oldsz);
+            return Err(());
+        }
+    }
+    Ok(newsz)
+}
+
+// Deallocate user pages to bring the process size from oldsz to
+// newsz.  oldsz and newsz need not be page-aligned, nor does newsz
+// need to be less than oldsz.  oldsz can be larger than the actual
+// process size.  Returns the new process size.
+pub fn uvmdealloc(pgtbl: &mut PageTable, oldsz: usize, newsz: usize) -> usize {
+    if newsz >= oldsz {
+        return oldsz;
+    }
+
+    if PGROUNDUP!(newsz) < PGROUNDUP!(oldsz) {
+        let npages = (PGROUNDUP!(oldsz) - PGROUNDUP!(newsz)) / PGSIZE;
+        uvmunmap(pgtbl, PGROUNDUP!(newsz), npages, true);
+    }
+    newsz
+}
+
+// Recursively free page-table pages.
+// All leaf mappings must already have been removed.
+fn freewalk(pgtbl: &mut PageTable) {
+    // there are 2^9 = 512 PTEs in a page table.
+    for i in 0..512 {
+        let pte = pgtbl.ptes[i];
+        if (pte & PTE_V) != 0 && (pte & (PTE_R | PTE_W | PTE_X)) == 0 {
+            // this PTE points to a lower-level page table.
+            let child = PTE2PA!(pte) as *mut PageTable;
+            freewalk(unsafe { &mut *child });
+            pgtbl.ptes[i] = 0;
+        } else if (pte & PTE_V) != 0 {
+            panic!("freewalk: leaf");
+        }
+    }
+    kfree(pgtbl as *mut PageTable as *mut u8);
+}
+
+// Free user memory pages,
+// then free page-table pages.
+pub fn uvmfree(pgtbl: &mut PageTable, sz: usize) {
+    if sz > 0 {
+        uvmunmap(pgtbl, 0, PGROUNDUP!(sz) / PGSIZE, true);
+    }
+    freewalk(pgtbl);
+}
+
+// Given a parent process's page table, share
+// its memory with a child's page table.
+// Writable pages become read-only copy-on-write pages in
+// both; the first write to one gets a private copy
+// (see cowfault()).
+// unmaps anything already mapped in the child on failure.
+pub fn uvmcopy(old: &mut PageTable, new: &mut PageTable, sz: usize) -> Result<(), ()> {
+    uvmshare(old, new, 0, sz, true)
+}
+
+// Map the pages of old in [va, va+len) into new at the same
+// addresses, sharing the physical pages. If cow, writable pages
+// become copy-on-write in both.
+// unmaps anything already mapped in new by this call on failure.
+pub fn uvmshare(
+    old: &mut PageTable,
+    new: &mut PageTable,
+    va: usize,
+    len: usize,
+    cow: bool,
+) -> Result<(), ()> {
+    for i in (va..va + len).step_by(PGSIZE) {
+        // new faults in untouched pages itself.
+        let pte = match walk(old, i, false) {
+            Ok(pte) if *pte & PTE_V != 0 => pte,
+            _ => continue,
+        };
+        if cow && *pte & PTE_W != 0 {
+            *pte = (*pte & !PTE_W) | PTE_COW;
+        }
+        let pa = PTE2PA!(*pte) as usize;
+        let flags = PTE_FLAGS!(*pte);
+        if !mappages(new, i, pa, PGSIZE, flags) {
+            uvmunmap(new, va, (i - va) / PGSIZE, true);
+            sfence_vma();
+            return Err(());
+        }
+        kdup(pa);
+    }
+    // old's PTEs may have lost PTE_W.
+    sfence_vma();
+    Ok(())
+}
+
+// Handle a write to the copy-on-write page at va:
+// give the page table a private, writable copy of the page,
+// or just make the page writable again if no one else
+// shares it anymore.
+// Returns Err if va isn't a copy-on-write user page, or if
+// out of memory.
+pub fn cowfault(pgtbl: &mut PageTable, va: usize) -> Result<(), ()> {
+    if va >= MAXVA as usize {
+        return Err(());
+    }
+    let va = PGROUNDDOWN!(va);
+    let pte = walk(pgtbl, va, false)?;
+    if *pte & PTE_V == 0 || *pte & PTE_U == 0 || *pte & PTE_COW == 0 {
+        return Err(());
+    }
+    let pa = PTE2PA!(*pte) as usize;
+    let flags = (PTE_FLAGS!(*pte) & !PTE_COW) | PTE_W;
+
+    if krefcnt(pa) == 1 {
+        // the last one sharing it.
+        *pte = PA2PTE!(pa as u64) | flags;
+    } else {
+        let mem = kalloc().ok_or(())?;
+        unsafe { memmove(mem, pa as *const u8, PGSIZE) };
+        *pte = PA2PTE!(mem as u64) | flags;
+        kfree(pa as *mut u8);
+    }
+    sfence_vma();
+    Ok(())
+}
+
+// Allocate and map a zeroed page for va, if va is below the
+// process size sz but its page was never touched: sbrk() only
+// grows sz, and pages are allocated on first use.
+// Returns Err if va isn't such a page, or if out of memory.
+pub fn uvmlazy(pgtbl: &mut PageTable, va: usize, sz: usize) -> Result<(), ()> {
+    if va >= sz || va >= MAXVA as usize {
+        return Err(());
+    }
+    let va = PGROUNDDOWN!(va);
+    if let Ok(pte) = walk(pgtbl, va, false) {
+        if *pte & PTE_V != 0 {
+            // mapped, but the access isn't allowed.
+            return Err(());
+        }
+    }
+    let mem = kalloc().ok_or(())?;
+    unsafe { memset(mem, 0, PGSIZE) };
+    if !mappages(pgtbl, va, mem as usize, PGSIZE, PTE_R | PTE_W | PTE_U) {
+        kfree(mem);
+        return Err(());
+    }
+    Ok(())
+}
+
+// Look up user virtual address va for the kernel to access on the
+// user's behalf with perm (PTE_R or PTE_W). Returns the physical
+// address, or None if the user couldn't make that access itself.
+// If pgtbl is the current process's page table, first fill in the
+// page for va if it was never touched, so that system calls can use
+// memory from sbrk() and mmap() like user code. Pages of file
+// mappings can't be read in with a spin lock held; fileread() and
+// filewrite() fault theirs in before taking any (see vmaprefault()).
+fn uvmaddr(pgtbl: &mut PageTable, va: usize, perm: u64) -> Option<usize> {
+    if walkaddr(pgtbl, va).is_none() {
+        let p = unsafe { &proc[myproc()?] };
+        if !ptr::eq(p.pagetable, pgtbl) {
+            return None;
+        }
+        uvmlazy(pgtbl, va, p.sz as usize)
+            .or_else(|_| vmafault(p, va, perm, !holding_locks()))
+            .ok()?;
+    }
+    let pte = walk(pgtbl, va, false).ok()?;
+    let need = PTE_V | PTE_U | perm;
+    if *pte & need != need {
+        return None;
+    }
+    Some(PTE2PA!(*pte) as usize)
+}
+
+// mark a PTE invalid for user access.
+// used by exec for the user stack guard page.
+pub fn uvmclear(pgtbl: &mut PageTable, va: usize) {
+    let pte = walk(pgtbl, va, false).expect("uvmclear");
+    *pte &= !PTE_U;
+}
+
+// Copy from kernel to user.
+// Copy len bytes from src to virtual address dstva in a given page table.
+// Returns Err if any of it isn't user memory that the user could write.
+pub fn copyout(
+    pgtbl: &mut PageTable,
+    dstva: usize,
+    src: *const u8,
+    len: usize,
+) -> Result<(), ()> {
+    let mut dstva = dstva;
+    let mut src = src;
+    let mut len = len;
+    while len > 0 {
+        let va0 = PGROUNDDOWN!(dstva);
+        // don't write through to a page shared copy-on-write.
+        if let Ok(pte) = walk(pgtbl, va0, false) {
+            if *pte & PTE_COW != 0 {
+                cowfault(pgtbl, va0)?;
+            }
+        }
+        let pa0 = uvmaddr(pgtbl, va0, PTE_W).ok_or(())?;
+        let n = min(PGSIZE - (dstva - va0), len);
+        unsafe {
+            memmove((pa0 + (dstva - va0)) as *mut u8, src, n);
+            src = src.add(n);
+        }
+        // the hardware only marks user stores dirty; mark this one
+        // too, so munmap() writes it back to a shared mapping's file.
+        *walk(pgtbl, va0, false)? |= PTE_D;
+        len -= n;
+        dstva = va0 + PGSIZE;
+    }
+    Ok(())
+}
+
+// Copy from user to kernel.
+// Copy len bytes to dst from virtual address srcva in a given page table.
+// Returns Err if any of it isn't user memory that the user could read.
+pub fn copyin(
+    pgtbl: &mut PageTable,
+    dst: *mut u8,
+    srcva: usize,
+    len: usize,
+) -> Result<(), ()> {
+    let mut dst = dst;
+    let mut srcva = srcva;
+    let mut len = len;
+    while len > 0 {
+        let va0 = PGROUNDDOWN!(srcva);
+        let pa0 = uvmaddr(pgtbl, va0, PTE_R).ok_or(())?;
+        let n = min(PGSIZE - (srcva - va0), len);
+        unsafe {
+            memmove(dst, (pa0 + (srcva - va0)) as *const u8, n);
+            dst = dst.add(n);
+        }
+        len -= n;
+        srcva = va0 + PGSIZE;
+    }
+    Ok(())
+}
+
+// Copy a null-terminated string from user to kernel.
+// Copy bytes to dst from virtual address srcva in a given page table,
+// until a '\0', or dst is full. Returns the length of the string,
+// not counting the '\0', or Err if dst filled up first or the string
+// runs into memory that the user couldn't read.
+pub fn copyinstr(pgtbl: &mut PageTable, dst: &mut [u8], srcva: usize) -> Result<usize, ()> {
+    let mut srcva = srcva;
+    let mut got = 0;
+    while got < dst.len() {
+        let va0 = PGROUNDDOWN!(srcva);
+        let pa0 = uvmaddr(pgtbl, va0, PTE_R).ok_or(())?;
+        let n = min(PGSIZE - (srcva - va0), dst.len() - got);
+        let src = (pa0 + (srcva - va0)) as *const u8;
+        for i in 0..n {
+            let c = unsafe { *src.add(i) };
+            dst[got] = c;
+            if c == 0 {
+                return Ok(got);
+            }
+            got += 1;
+        }
+        srcva = va0 + PGSIZE;
+    }
+    Err(())
 }
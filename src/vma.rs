@@ -0,0 +1,349 @@
+// Memory-mapped regions: mmap() and munmap().
+//
+// Each process has NVMA virtual memory areas in Proc::vma. They are
+// placed top-down below the trapframe, and p.sz may not grow into
+// them (see vmafloor()).
+//
+// Nothing is mapped by mmap() itself: usertrap() calls vmafault()
+// on the first touch of a page, which fills it with zeroes or with
+// the file's contents. Pages of shared file mappings that were
+// written (PTE_D) go back to the file when they are unmapped, by
+// munmap(), exit() or exec().
+
+use core::cmp::{max, min};
+use core::ptr::null_mut;
+
+use crate::file::{fileclose, filedup, File, FileType};
+use crate::fs::log::{begin_op, end_op};
+use crate::fs::{ilock, iunlock, readi, writei};
+use crate::kalloc::{kalloc, kfree};
+use crate::mem_utils::memset;
+use crate::memolayout::TRAPFRAME;
+use crate::params::{BSIZE, MAXOPBLOCKS, NVMA};
+use crate::proc::Proc;
+use crate::riscv::{PGSIZE, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+use crate::vm::{mappages, uvmshare, uvmunmap, walk, PageTable};
+use crate::{PGROUNDDOWN, PGROUNDUP, PTE2PA};
+
+// mmap() prot
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+pub const PROT_EXEC: i32 = 0x4;
+
+// mmap() flags
+pub const MAP_SHARED: i32 = 0x01;
+pub const MAP_PRIVATE: i32 = 0x02;
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub used: bool,
+    pub addr: usize,     // page-aligned start
+    pub len: usize,      // a multiple of PGSIZE
+    pub perm: u64,       // PTE bits for its pages
+    pub shared: bool,    // MAP_SHARED: writes go back to the file
+    pub file: *mut File, // null for anonymous mappings
+    pub off: usize,      // file offset of addr
+}
+
+impl Vma {
+    pub const fn empty() -> Self {
+        Vma {
+            used: false,
+            addr: 0,
+            len: 0,
+            perm: 0,
+            shared: false,
+            file: null_mut(),
+            off: 0,
+        }
+    }
+
+    fn contains(&self, va: usize) -> bool {
+        self.used && self.addr <= va && va < self.addr + self.len
+    }
+}
+
+fn prot2perm(prot: i32) -> u64 {
+    let mut perm = PTE_U;
+    if prot & PROT_READ != 0 {
+        perm |= PTE_R;
+    }
+    if prot & PROT_WRITE != 0 {
+        // writable but not readable isn't a valid PTE.
+        perm |= PTE_R | PTE_W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= PTE_X;
+    }
+    perm
+}
+
+// Lowest address in use by p's mappings, or the trapframe
+// if it has none: the limit for p.sz and for the next mapping.
+pub fn vmafloor(p: &Proc) -> usize {
+    p.vma
+        .iter()
+        .filter(|v| v.used)
+        .map(|v| v.addr)
+        .min()
+        .unwrap_or(TRAPFRAME)
+}
+
+// Map len bytes of f starting at off, or of zeroes if f is None,
+// into p's address space.
+// Anonymous mappings must be private.
+// Returns the address of the mapping.
+pub fn mmap(
+    p: &mut Proc,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    f: Option<&mut File>,
+    off: usize,
+) -> Result<usize, ()> {
+    if len == 0 || len > TRAPFRAME || off % PGSIZE != 0 {
+        return Err(());
+    }
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || flags & !(MAP_SHARED | MAP_PRIVATE | MAP_ANONYMOUS) != 0
+    {
+        return Err(());
+    }
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(()),
+    };
+    let file = match f {
+        Some(f) => {
+            if f.type_ != FileType::Inode || !f.readable {
+                return Err(());
+            }
+            if shared && prot & PROT_WRITE != 0 && !f.writable {
+                return Err(());
+            }
+            // offsets into an inode are 32 bits.
+            if off
+                .checked_add(len)
+                .map_or(true, |end| end > u32::MAX as usize)
+            {
+                return Err(());
+            }
+            f as *mut File
+        }
+        None if shared => return Err(()),
+        None => null_mut(),
+    };
+
+    let len = PGROUNDUP!(len);
+    let top = vmafloor(p);
+    if top < len || top - len < PGROUNDUP!(p.sz as usize) {
+        return Err(());
+    }
+    let addr = top - len;
+    let v = p.vma.iter_mut().find(|v| !v.used).ok_or(())?;
+    *v = Vma {
+        used: true,
+        addr,
+        len,
+        perm: prot2perm(prot),
+        shared,
+        file,
+        off,
+    };
+    if !file.is_null() {
+        filedup(unsafe { &mut *file });
+    }
+    Ok(addr)
+}
+
+// Unmap [addr, addr+len) from one of p's mappings, writing dirty
+// shared pages back to the file.
+// The range must be at the start or the end of the mapping
+// (or all of it), so a mapping never gets a hole.
+pub fn munmap(p: &mut Proc, addr: usize, len: usize) -> Result<(), ()> {
+    if addr % PGSIZE != 0 || len == 0 {
+        return Err(());
+    }
+    let end = addr.checked_add(PGROUNDUP!(len)).ok_or(())?;
+    let pgtbl = unsafe { &mut *p.pagetable };
+    let v = p.vma.iter_mut().find(|v| v.contains(addr)).ok_or(())?;
+    if end > v.addr + v.len || (addr != v.addr && end != v.addr + v.len) {
+        return Err(());
+    }
+
+    vmaunmap(v, pgtbl, addr, end - addr);
+    if addr == v.addr {
+        v.addr = end;
+        v.off += end - addr;
+    }
+    v.len -= end - addr;
+    if v.len == 0 {
+        vmaclose(v);
+    }
+    Ok(())
+}
+
+// Fill in the page of p's mappings that va is in, on a page fault
+// for an access needing perm (PTE_R, PTE_W or PTE_X).
+// Reading a file's page may sleep; callers that can't (copyout()
+// and copyin() with a spin lock held) pass may_sleep false, and only
+// get anonymous pages.
+// Returns Err if va isn't in a mapping that allows the access.
+pub fn vmafault(p: &Proc, va: usize, perm: u64, may_sleep: bool) -> Result<(), ()> {
+    let va = PGROUNDDOWN!(va);
+    let v = p.vma.iter().find(|v| v.contains(va)).ok_or(())?;
+    if v.perm & perm == 0 {
+        return Err(());
+    }
+    if !v.file.is_null() && !may_sleep {
+        return Err(());
+    }
+    let pgtbl = unsafe { &mut *p.pagetable };
+    if let Ok(pte) = walk(pgtbl, va, false) {
+        if *pte & PTE_V != 0 {
+            // already there, so the access isn't allowed.
+            return Err(());
+        }
+    }
+
+    let mem = kalloc().ok_or(())?;
+    unsafe { memset(mem, 0, PGSIZE) };
+    if !v.file.is_null() {
+        // past the end of the file stays zero.
+        let ip = unsafe { &mut *(*v.file).ip };
+        ilock(ip);
+        let r = readi(
+            ip,
+            false,
+            mem as u64,
+            (v.off + va - v.addr) as u32,
+            PGSIZE as u32,
+        );
+        iunlock(ip);
+        if r < 0 {
+            kfree(mem);
+            return Err(());
+        }
+    }
+    if !mappages(pgtbl, va, mem as usize, PGSIZE, v.perm) {
+        kfree(mem);
+        return Err(());
+    }
+    Ok(())
+}
+
+// Fill in the untouched pages of p's file mappings in [va, va+len),
+// before a system call copies to or from them with a lock held,
+// when copyout() and copyin() couldn't read them in (or would
+// deadlock on an inode lock held for the same file).
+// Problems are left for the copy to report.
+pub fn vmaprefault(p: &Proc, va: usize, len: usize, perm: u64) {
+    let end = va.saturating_add(len);
+    for v in p.vma.iter().filter(|v| v.used && !v.file.is_null()) {
+        let start = max(PGROUNDDOWN!(va), v.addr);
+        for a in (start..min(end, v.addr + v.len)).step_by(PGSIZE) {
+            // Err for pages already there, too.
+            let _ = vmafault(p, a, perm, true);
+        }
+    }
+}
+
+// Give np the mappings of p, its parent in fork(). Pages already
+// in place are shared: outright for shared mappings, copy-on-write
+// for private ones.
+pub fn vmacopy(p: &Proc, np: &mut Proc) -> Result<(), ()> {
+    let old = unsafe { &mut *p.pagetable };
+    let new = unsafe { &mut *np.pagetable };
+    for (v, nv) in p.vma.iter().zip(np.vma.iter_mut()) {
+        if !v.used {
+            continue;
+        }
+        uvmshare(old, new, v.addr, v.len, !v.shared)?;
+        *nv = *v;
+    }
+    // take the file references last, so that freeproc() of a
+    // child whose fork failed has none to drop.
+    for nv in np.vma.iter() {
+        if nv.used && !nv.file.is_null() {
+            filedup(unsafe { &mut *nv.file });
+        }
+    }
+    Ok(())
+}
+
+// Unmap all of the mappings in vma from pgtbl, writing back dirty
+// shared pages, and drop their file references. For exit() and exec().
+// Must not be called inside a file system transaction.
+pub fn vmaunmapall(vma: &mut [Vma; NVMA], pgtbl: &mut PageTable) {
+    for v in vma.iter_mut().filter(|v| v.used) {
+        vmaunmap(v, pgtbl, v.addr, v.len);
+        vmaclose(v);
+    }
+}
+
+// Unmap what is left of p's mappings, without writing anything
+// back. For freeproc(): exit() has already unmapped them properly,
+// and a failed fork() took no file references (see vmacopy()).
+pub fn vmafree(p: &mut Proc) {
+    let pgtbl = unsafe { &mut *p.pagetable };
+    for v in p.vma.iter_mut().filter(|v| v.used) {
+        uvmunmap(pgtbl, v.addr, v.len / PGSIZE, true);
+        *v = Vma::empty();
+    }
+}
+
+fn vmaclose(v: &mut Vma) {
+    if !v.file.is_null() {
+        fileclose(unsafe { &mut *v.file });
+    }
+    *v = Vma::empty();
+}
+
+// Unmap [va, va+len) of v, writing dirty pages back to the file
+// if v is shared.
+fn vmaunmap(v: &Vma, pgtbl: &mut PageTable, va: usize, len: usize) {
+    if v.shared {
+        for a in (va..va + len).step_by(PGSIZE) {
+            if let Ok(pte) = walk(pgtbl, a, false) {
+                if *pte & PTE_V != 0 && *pte & PTE_D != 0 {
+                    let pa = PTE2PA!(*pte) as usize;
+                    writeback(unsafe { &*v.file }, v.off + a - v.addr, pa);
+                }
+            }
+        }
+    }
+    uvmunmap(pgtbl, va, len / PGSIZE, true);
+}
+
+// Write the page at pa to f at off, but not past the end of the
+// file: a mapping doesn't make its file grow.
+fn writeback(f: &File, off: usize, pa: usize) {
+    // a few blocks at a time, like filewrite().
+    let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+    let ip = unsafe { &mut *f.ip };
+    let mut i = 0;
+    while i < PGSIZE {
+        begin_op();
+        ilock(ip);
+        let size = ip.size as usize;
+        let n = if off + i < size {
+            min(min(PGSIZE - i, max), size - off - i)
+        } else {
+            0
+        };
+        let r = if n > 0 {
+            writei(ip, false, (pa + i) as u64, (off + i) as u32, n as u32)
+        } else {
+            0
+        };
+        iunlock(ip);
+        end_op();
+
+        if r <= 0 || r as usize != n {
+            break;
+        }
+        i += n;
+    }
+}